@@ -1,3 +1,4 @@
+extern crate proc_macro;
 extern crate quote;
 #[macro_use]
 extern crate synstructure;
@@ -7,10 +8,34 @@ use std::collections::HashMap;
 use kg_display::fmt::*;
 use kg_utils::collections::SparseSet;
 use proc_macro2::{Ident, Span};
+use syn::ext::IdentExt;
 
 decl_derive!([Display, attributes(display)] => display_derive);
 
 
+/// Parses a format string literal at compile time and expands to an expression building the
+/// resulting `kg_display::fmt::FormatString<'static>`, spans included. Invalid format strings
+/// are reported as compile errors pointing at the offending part of the literal.
+#[proc_macro]
+pub fn format_string(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let lit = syn::parse_macro_input!(input as syn::LitStr);
+    let value = lit.value();
+    match FormatString::parse(&value) {
+        Ok(fmt_str) => format_string_tokens(&fmt_str).into(),
+        Err(err) => {
+            // the span can only be narrowed down if the literal has no escapes or raw delimiters
+            let span = err.span();
+            let span = if lit.token().to_string() == format!("\"{}\"", value) {
+                lit.token().subspan(span.start + 1..span.end.max(span.start + 1) + 1).unwrap_or_else(|| lit.span())
+            } else {
+                lit.span()
+            };
+            syn::Error::new(span, format!("invalid format string: {}", err)).to_compile_error().into()
+        }
+    }
+}
+
+
 fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
     fn err_msg<S: Into<String>>(msg: S, v: &synstructure::VariantInfo) -> String {
         use std::fmt::Write;
@@ -47,7 +72,7 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
                 if let &syn::NestedMeta::Meta(syn::Meta::NameValue(syn::MetaNameValue { ref path, lit: syn::Lit::Str(ref s), .. })) = p {
                     if let Some(ident) = path.get_ident() {
                         if ident != "fmt" && ident != "alt" {
-                            params.insert(ident.to_string(), s.value());
+                            params.insert(ident.unraw().to_string(), s.value());
                         }
                     }
                 }
@@ -74,18 +99,21 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
         let mut bindings_set: SparseSet<usize> = SparseSet::with_capacity(v.bindings().len());
         let mut params_set: SparseSet<usize> = SparseSet::with_capacity(params.len());
 
-        let fmt_str = FormatString::parse(&fmt).expect(&err_msg("invalid format string", v));
+        let fmt_str = FormatString::parse(&fmt).unwrap_or_else(|e| {
+            panic!("{}", err_msg(format!("invalid format string: {}", e), v))
+        }).into_owned();
         fmt_str.each_argument(|arg| {
-            match *arg {
-                Argument::Next => panic!("{}", err_msg("default positional argument found, only named arguments are supported", v)),
-                Argument::Index(_) => panic!("{}", err_msg("positional argument found, only named arguments are supported", v)),
+            match **arg {
+                Argument::Next => panic!("{}", err_msg(format!("default positional argument found at position {}, only named arguments are supported", arg.span().start), v)),
+                Argument::Index(_) => panic!("{}", err_msg(format!("positional argument '{}' found at position {}, only named arguments are supported", arg, arg.span().start), v)),
+                Argument::Path(_) => panic!("{}", err_msg(format!("field path '{}' found at position {}, only named arguments are supported", arg, arg.span().start), v)),
                 Argument::Name(ref name) => {
                     if let Some((i, _)) = params.iter().enumerate().find(|(_, p)| &p.0 == name) {
                         params_set.insert(i);
-                    } else if let Some((i, _)) = v.bindings().iter().enumerate().find(|(_, bi)| bi.binding == name) {
+                    } else if let Some((i, _)) = v.bindings().iter().enumerate().find(|(_, bi)| bi.binding.unraw() == name) {
                         bindings_set.insert(i);
                     } else {
-                        panic!("{}", err_msg(format!("unknown argument '{}'", name), v));
+                        panic!("{}", err_msg(format!("unknown argument '{}' at position {}", name, arg.span().start), v));
                     }
                 },
             }
@@ -117,38 +145,43 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
                 let mut bindings_set: SparseSet<usize> = SparseSet::with_capacity(v.bindings().len());
                 let mut params_set: SparseSet<usize> = SparseSet::with_capacity(params.len());
 
-                let fmt_str = FormatString::parse(&fmt).expect(&err_msg("invalid format string", v));
+                let fmt_str = FormatString::parse(&fmt).unwrap_or_else(|e| {
+                    panic!("{}", err_msg(format!("invalid format string: {}", e), v))
+                }).into_owned();
                 fmt_str.each_argument(|arg| {
-                    match *arg {
-                        Argument::Next => panic!("{}", err_msg("default positional argument found, only named arguments are supported", v)),
-                        Argument::Index(_) => panic!("{}", err_msg("positional argument found, only named arguments are supported", v)),
+                    match **arg {
+                        Argument::Next => panic!("{}", err_msg(format!("default positional argument found at position {}, only named arguments are supported", arg.span().start), v)),
+                        Argument::Index(_) => panic!("{}", err_msg(format!("positional argument '{}' found at position {}, only named arguments are supported", arg, arg.span().start), v)),
+                        Argument::Path(_) => panic!("{}", err_msg(format!("field path '{}' found at position {}, only named arguments are supported", arg, arg.span().start), v)),
                         Argument::Name(ref name) => {
                             if let Some((i, _)) = params.iter().enumerate().find(|(_, p)| &p.0 == name) {
                                 params_set.insert(i);
-                            } else if let Some((i, _)) = v.bindings().iter().enumerate().find(|(_, bi)| bi.binding == name) {
+                            } else if let Some((i, _)) = v.bindings().iter().enumerate().find(|(_, bi)| bi.binding.unraw() == name) {
                                 bindings_set.insert(i);
                             } else {
-                                panic!("{}", err_msg(format!("unknown argument '{}'", name), v));
+                                panic!("{}", err_msg(format!("unknown argument '{}' at position {}", name, arg.span().start), v));
                             }
                         },
                     }
                     true
                 });
-                Some((fmt, fmt_str, bindings_set, params_set))
+                Some((fmt_str, bindings_set, params_set))
             } else {
                 None
             }
         };
 
-        fmts.push((params, (fmt, fmt_str, bindings_set, params_set), alt));
+        fmts.push((params, (fmt_str, bindings_set, params_set), alt));
     }
 
     let mut fmt_it = fmts.into_iter();
     let display_body = s.each_variant(|v| {
         let fmt_ = fmt_it.next().unwrap();
         let params = fmt_.0;
-        let (fmt, _, bset, pset) = fmt_.1;
-        if let Some((fmt_alt, _, bset_alt, pset_alt)) = fmt_.2 {
+        let (fmt_str, bset, pset) = fmt_.1;
+        let fmt = fmt_str.to_string();
+        if let Some((fmt_str_alt, bset_alt, pset_alt)) = fmt_.2 {
+            let fmt_alt = fmt_str_alt.to_string();
             let args = v.bindings().iter().enumerate().filter_map(|(index, bi)| {
                 if bset.contains(&index) {
                     let ref id = bi.binding;
@@ -160,7 +193,7 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
 
             let p_args = params.iter().enumerate().filter_map(|(index, p)| {
                 if pset.contains(&index) {
-                    let id = Ident::new(&p.0, Span::call_site());
+                    let id = param_ident(&p.0);
                     let value: syn::Expr = syn::parse_str(&p.1).expect(&format!("cannot parse expression: `{}` for parameter '{}'", &p.1, &p.0));
                     Some(quote! { #id = #value })
                 } else {
@@ -179,7 +212,7 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
 
             let p_args_alt = params.iter().enumerate().filter_map(|(index, p)| {
                 if pset_alt.contains(&index) {
-                    let id = Ident::new(&p.0, Span::call_site());
+                    let id = param_ident(&p.0);
                     let value: syn::Expr = syn::parse_str(&p.1).expect(&format!("cannot parse expression: `{}` for parameter '{}'", &p.1, &p.0));
                     Some(quote! { #id = #value })
                 } else {
@@ -206,7 +239,7 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
 
             let p_args = params.iter().enumerate().filter_map(|(index, p)| {
                 if pset.contains(&index) {
-                    let id = Ident::new(&p.0, Span::call_site());
+                    let id = param_ident(&p.0);
                     let value: syn::Expr = syn::parse_str(&p.1).expect(&format!("cannot parse expression: `{}` for parameter '{}'", &p.1, &p.0));
                     Some(quote! { #id = #value })
                 } else {
@@ -237,6 +270,11 @@ fn display_derive(mut s: synstructure::Structure) -> proc_macro2::TokenStream {
 }
 
 
+fn param_ident(name: &str) -> Ident {
+    syn::parse_str::<Ident>(name).unwrap_or_else(|_| Ident::new_raw(name, Span::call_site()))
+}
+
+
 fn find_display_attr(attrs: &[syn::Attribute]) -> Option<Vec<syn::NestedMeta>> {
     let doc_path: syn::Path = syn::Ident::new("doc", Span::call_site()).into();
 
@@ -268,3 +306,135 @@ fn find_display_attr(attrs: &[syn::Attribute]) -> Option<Vec<syn::NestedMeta>> {
     }
     disp
 }
+
+
+fn format_string_tokens(fmt_str: &FormatString) -> proc_macro2::TokenStream {
+    fn span_tokens(span: kg_display::fmt::Span) -> proc_macro2::TokenStream {
+        let (start, end) = (span.start, span.end);
+        quote! { ::kg_display::fmt::Span::new(#start, #end) }
+    }
+
+    fn argument_tokens(arg: &Spanned<Argument>) -> proc_macro2::TokenStream {
+        let node = match **arg {
+            Argument::Next => quote! { ::kg_display::fmt::Argument::Next },
+            Argument::Index(index) => quote! { ::kg_display::fmt::Argument::Index(#index) },
+            Argument::Name(ref name) => {
+                let name: &str = name;
+                quote! { ::kg_display::fmt::Argument::Name(::std::borrow::Cow::Borrowed(#name)) }
+            }
+            Argument::Path(ref path) => {
+                let segments = path.iter().map(|segment| match *segment {
+                    PathSegment::Field(ref name) => {
+                        let name: &str = name;
+                        quote! { ::kg_display::fmt::PathSegment::Field(::std::borrow::Cow::Borrowed(#name)) }
+                    }
+                    PathSegment::Index(index) => quote! { ::kg_display::fmt::PathSegment::Index(#index) },
+                });
+                quote! { ::kg_display::fmt::Argument::Path(::std::vec![#(#segments),*].into_boxed_slice()) }
+            }
+        };
+        let span = span_tokens(arg.span());
+        quote! { ::kg_display::fmt::Spanned::new(#node, #span) }
+    }
+
+    fn count_tokens(count: &Spanned<Count>) -> proc_macro2::TokenStream {
+        let node = match **count {
+            Count::Argument(ref arg) => {
+                let arg = argument_tokens(arg);
+                quote! { ::kg_display::fmt::Count::Argument(#arg) }
+            }
+            Count::Value(value) => quote! { ::kg_display::fmt::Count::Value(#value) },
+        };
+        let span = span_tokens(count.span());
+        quote! { ::kg_display::fmt::Spanned::new(#node, #span) }
+    }
+
+    fn spec_tokens(spec: &Spanned<FormatSpec>) -> proc_macro2::TokenStream {
+        let mut tokens = quote! { ::kg_display::fmt::FormatSpec::new() };
+        if let Some(fill_align) = spec.fill_align() {
+            let fill = match fill_align.fill() {
+                Some(c) => quote! { ::std::option::Option::Some(#c) },
+                None => quote! { ::std::option::Option::None },
+            };
+            let align = match fill_align.align() {
+                Align::Left => quote! { Left },
+                Align::Center => quote! { Center },
+                Align::Right => quote! { Right },
+            };
+            tokens.extend(quote! { .with_fill_align(::kg_display::fmt::FillAlign::new(#fill, ::kg_display::fmt::Align::#align)) });
+        }
+        if let Some(sign) = spec.sign() {
+            let sign = match sign {
+                Sign::Plus => quote! { Plus },
+                Sign::Minus => quote! { Minus },
+            };
+            tokens.extend(quote! { .with_sign(::kg_display::fmt::Sign::#sign) });
+        }
+        if spec.alter() {
+            tokens.extend(quote! { .with_alter(true) });
+        }
+        if spec.zero() {
+            tokens.extend(quote! { .with_zero(true) });
+        }
+        if let Some(width) = spec.width() {
+            let width = count_tokens(width);
+            tokens.extend(quote! { .with_spanned_width(#width) });
+        }
+        if let Some(precision) = spec.precision() {
+            let node = match **precision {
+                Precision::Count(ref count) => {
+                    let count = count_tokens(count);
+                    quote! { ::kg_display::fmt::Precision::Count(#count) }
+                }
+                Precision::Star => quote! { ::kg_display::fmt::Precision::Star },
+            };
+            let span = span_tokens(precision.span());
+            tokens.extend(quote! { .with_spanned_precision(::kg_display::fmt::Spanned::new(#node, #span)) });
+        }
+        let format_type = match *spec.format_type() {
+            FormatType::Display => quote! { Display },
+            FormatType::Debug => quote! { Debug },
+            FormatType::DebugLowerHex => quote! { DebugLowerHex },
+            FormatType::DebugUpperHex => quote! { DebugUpperHex },
+            FormatType::Octal => quote! { Octal },
+            FormatType::LowerHex => quote! { LowerHex },
+            FormatType::UpperHex => quote! { UpperHex },
+            FormatType::Pointer => quote! { Pointer },
+            FormatType::Binary => quote! { Binary },
+            FormatType::LowerExp => quote! { LowerExp },
+            FormatType::UpperExp => quote! { UpperExp },
+            FormatType::Custom(ref name) => quote! { Custom(::std::string::String::from(#name)) },
+        };
+        tokens.extend(quote! { .with_format_type(::kg_display::fmt::FormatType::#format_type) });
+        let span = span_tokens(spec.span());
+        quote! { ::kg_display::fmt::Spanned::new(#tokens, #span) }
+    }
+
+    let items = fmt_str.items().iter().map(|item| {
+        let node = match **item {
+            FormatStringItem::Text(ref text) => {
+                let text: &str = text;
+                quote! { ::kg_display::fmt::FormatStringItem::Text(::std::borrow::Cow::Borrowed(#text)) }
+            }
+            FormatStringItem::Escape(c) => quote! { ::kg_display::fmt::FormatStringItem::Escape(#c) },
+            FormatStringItem::Format(ref fmt) => {
+                let arg = argument_tokens(fmt.arg());
+                let spec = match fmt.spec() {
+                    Some(spec) => {
+                        let spec = spec_tokens(spec);
+                        quote! { ::std::option::Option::Some(#spec) }
+                    }
+                    None => quote! { ::std::option::Option::None },
+                };
+                quote! { ::kg_display::fmt::FormatStringItem::Format(::kg_display::fmt::Format::from_parts(#arg, #spec)) }
+            }
+            FormatStringItem::Error(_) => unreachable!(),
+        };
+        let span = span_tokens(item.span());
+        quote! { ::kg_display::fmt::Spanned::new(#node, #span) }
+    });
+
+    quote! {
+        ::kg_display::fmt::FormatString::<'static>::from(::std::vec![#(#items),*])
+    }
+}
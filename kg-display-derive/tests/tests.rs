@@ -4,6 +4,7 @@ extern crate kg_display_derive;
 
 
 use kg_display::ListDisplay;
+use kg_display::fmt::{FormatString, Span};
 
 #[derive(Debug, Display)]
 enum TestEnum {
@@ -27,6 +28,13 @@ struct TestStruct {
     field2: usize,
 }
 
+#[derive(Debug, Display)]
+#[display(fmt = "{r#type} {größe} {type:width$}", width = "4")]
+struct TestRawStruct {
+    r#type: &'static str,
+    größe: usize,
+}
+
 #[test]
 fn display_enum_struct_variant() {
     let e = TestEnum::Struct {
@@ -67,3 +75,27 @@ fn display_struct() {
 
     assert_eq!("test struct with fields - field1: string1.", format!("{}", e));
 }
+
+#[test]
+fn display_raw_and_unicode_fields() {
+    let e = TestRawStruct {
+        r#type: "ab",
+        größe: 12,
+    };
+
+    assert_eq!("ab 12 ab  ", format!("{}", e));
+}
+
+#[test]
+fn format_string_macro() {
+    static INPUT: &str = "x = {name:*>w$.3} {{ {0:+#010x?} {:.*} {größe:e}";
+    let f: FormatString<'static> = format_string!("x = {name:*>w$.3} {{ {0:+#010x?} {:.*} {größe:e}");
+    let p = FormatString::parse(INPUT).unwrap();
+
+    assert_eq!(f, p);
+    assert_eq!(f.to_string(), INPUT);
+    let spans: Vec<Span> = f.items().iter().map(|i| i.span()).collect();
+    let parsed_spans: Vec<Span> = p.items().iter().map(|i| i.span()).collect();
+    assert_eq!(spans, parsed_spans);
+    assert_eq!(f.argument_uses()[1].arg().span(), Span::new(12, 13));
+}
@@ -0,0 +1,43 @@
+#![feature(test)]
+
+extern crate test;
+
+use kg_display::fmt::{ArgList, FormatString};
+use test::Bencher;
+
+const FORMAT: &str = "{time:>12.3} [{level:<5}] {user}@{host}: request {id:#010x} took {ms:.2} ms ({bytes} bytes, {{cached}})";
+
+fn bench_render<F: FnMut(&ArgList, &mut String)>(b: &mut Bencher, mut render: F) {
+    let (time, level, user, host, id, ms, bytes) = (1234.5678, "INFO", "ann", "db-1", 48879u32, 12.3456, 1024u64);
+    let args = ArgList::new()
+        .named("time", &time)
+        .named("level", &level)
+        .named("user", &user)
+        .named("host", &host)
+        .named("id", &id)
+        .named("ms", &ms)
+        .named("bytes", &bytes);
+    let mut out = String::with_capacity(256);
+    b.iter(|| {
+        out.clear();
+        render(&args, &mut out);
+        test::black_box(&out);
+    });
+}
+
+#[bench]
+fn render_format_string(b: &mut Bencher) {
+    let f = FormatString::parse(FORMAT).unwrap();
+    bench_render(b, |args, out| f.render(args, out).unwrap());
+}
+
+#[bench]
+fn render_compiled_format(b: &mut Bencher) {
+    let f = FormatString::parse(FORMAT).unwrap().compile();
+    bench_render(b, |args, out| f.render(args, out).unwrap());
+}
+
+#[bench]
+fn parse_and_render(b: &mut Bencher) {
+    bench_render(b, |args, out| FormatString::parse(FORMAT).unwrap().render(args, out).unwrap());
+}
@@ -1,39 +1,366 @@
+use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::num::ParseIntError;
+use std::ops::{Deref, DerefMut};
 use std::str::{FromStr, CharIndices};
 
+use unicode_xid::UnicodeXID;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+pub mod visit;
+pub mod python;
+pub mod printf;
+mod analysis;
+mod render;
+mod compile;
+#[cfg(feature = "serde")]
+mod serialize;
+
+pub use self::visit::{Visit, VisitMut};
+pub use self::analysis::{FormatTrait, ArgumentRole, ArgumentUse};
+pub use self::render::{FormatArg, Args, ArgList, RenderOptions, RenderError};
+pub use self::compile::CompiledFormat;
+#[cfg(feature = "serde")]
+pub use self::serialize::{tree, FormatStringSeed};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    fn of_char(pos: usize, c: char) -> Span {
+        Span::new(pos, pos + c.len_utf8())
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+
 #[derive(Debug, Clone)]
-pub struct Format {
-    arg: Argument,
-    spec: Option<FormatSpec>,
+pub struct Spanned<T> {
+    node: T,
+    span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned { node, span }
+    }
+
+    pub fn node(&self) -> &T {
+        &self.node
+    }
+
+    pub fn node_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+
+    pub fn into_node(self) -> T {
+        self.node
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Spanned<U> {
+        Spanned::new(f(self.node), self.span)
+    }
+}
+
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T> DerefMut for Spanned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Spanned<T>) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: Eq> Eq for Spanned<T> {}
+
+impl<T: Hash> Hash for Spanned<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.node.hash(state)
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.node, f)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedChar {
+        c: char,
+        span: Span,
+    },
+    UnexpectedEnd {
+        pos: usize,
+    },
+    UnescapedBrace {
+        c: char,
+        span: Span,
+    },
+    InvalidInteger {
+        value: String,
+        span: Span,
+        err: ParseIntError,
+    },
+    UnknownFormatType {
+        value: String,
+        span: Span,
+    },
+    InvalidName {
+        value: String,
+        span: Span,
+    },
+    Unsupported {
+        feature: String,
+        span: Span,
+    },
+    MixedNumbering {
+        span: Span,
+    },
+}
+
+impl ParseError {
+    fn unexpected_char(pos: usize, c: char) -> ParseError {
+        ParseError::UnexpectedChar { c, span: Span::of_char(pos, c) }
+    }
+
+    fn unexpected_end(chars: &CharIndices) -> ParseError {
+        ParseError::UnexpectedEnd { pos: chars.offset() }
+    }
+
+    fn unsupported<S: Into<String>>(feature: S, span: Span) -> ParseError {
+        ParseError::Unsupported { feature: feature.into(), span }
+    }
+
+    pub fn span(&self) -> Span {
+        match *self {
+            ParseError::UnexpectedChar { span, .. } => span,
+            ParseError::UnexpectedEnd { pos } => Span::new(pos, pos),
+            ParseError::UnescapedBrace { span, .. } => span,
+            ParseError::InvalidInteger { span, .. } => span,
+            ParseError::UnknownFormatType { span, .. } => span,
+            ParseError::InvalidName { span, .. } => span,
+            ParseError::Unsupported { span, .. } => span,
+            ParseError::MixedNumbering { span } => span,
+        }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.span().start
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ParseError::UnexpectedChar { c, span } => write!(f, "unexpected char '{}' at position {}", c, span.start),
+            ParseError::UnexpectedEnd { .. } => write!(f, "unexpected end of input"),
+            ParseError::UnescapedBrace { c, span } => write!(f, "unescaped '{}' at position {}", c, span.start),
+            ParseError::InvalidInteger { ref value, span, ref err } => write!(f, "invalid integer '{}' at position {}: {}", value, span.start, err),
+            ParseError::UnknownFormatType { ref value, span } => write!(f, "unrecognized value type: '{}' at position {}", value, span.start),
+            ParseError::InvalidName { ref value, span } => write!(f, "invalid argument name '{}' at position {}", value, span.start),
+            ParseError::Unsupported { ref feature, span } => write!(f, "unsupported {} at position {}", feature, span.start),
+            ParseError::MixedNumbering { span } => write!(f, "cannot switch between manual and automatic field numbering at position {}", span.start),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ParseError::InvalidInteger { ref err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+
+fn peek(chars: &CharIndices) -> Option<char> {
+    chars.as_str().chars().next()
+}
+
+fn consume(chars: &mut CharIndices, c: char) -> bool {
+    if peek(chars) == Some(c) {
+        chars.next();
+        true
+    } else {
+        false
+    }
+}
+
+fn take_while<'a, F: Fn(char) -> bool>(chars: &mut CharIndices<'a>, f: F) -> &'a str {
+    let str = chars.as_str();
+    let len = str.find(|c| !f(c)).unwrap_or(str.len());
+    chars.advance_by(str[..len].chars().count()).unwrap();
+    &str[..len]
+}
+
+fn is_ident_start(c: char) -> bool {
+    c == '_' || UnicodeXID::is_xid_start(c)
+}
+
+fn is_ident_continue(c: char) -> bool {
+    UnicodeXID::is_xid_continue(c)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    name != "_" && name.starts_with(is_ident_start) && name.chars().all(is_ident_continue)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Format<'a> {
+    arg: Spanned<Argument<'a>>,
+    spec: Option<Spanned<FormatSpec<'a>>>,
 }
 
-impl Format {
-    fn from_chars(chars: &mut CharIndices) -> Result<Format, String> {
+impl<'a> Format<'a> {
+    pub fn new<A: Into<Argument<'a>>>(arg: A) -> Format<'a> {
+        Format {
+            arg: arg.into().into(),
+            spec: None,
+        }
+    }
+
+    pub fn from_parts(arg: Spanned<Argument<'a>>, spec: Option<Spanned<FormatSpec<'a>>>) -> Format<'a> {
+        Format { arg, spec }
+    }
+
+    pub fn with_spec(mut self, spec: FormatSpec<'a>) -> Format<'a> {
+        self.spec = Some(spec.into());
+        self
+    }
+
+    pub fn arg(&self) -> &Spanned<Argument<'a>> {
+        &self.arg
+    }
+
+    pub fn spec(&self) -> Option<&Spanned<FormatSpec<'a>>> {
+        self.spec.as_ref()
+    }
+
+    pub fn into_owned(self) -> Format<'static> {
+        Format {
+            arg: self.arg.map(Argument::into_owned),
+            spec: self.spec.map(|s| s.map(FormatSpec::into_owned)),
+        }
+    }
+
+    fn from_chars(chars: &mut CharIndices<'a>, options: &ParseOptions) -> Result<Format<'a>, ParseError> {
         let mut f = Format {
-            arg: Argument::Next,
+            arg: Argument::Next.into(),
             spec: None,
         };
-        if let Some((_, '{')) = chars.next() {
-            f.arg = Argument::from_chars(chars)?;
-        } else {
-            return Err(format!("format must start with '{{'"));
+        match chars.next() {
+            Some((_, '{')) => {
+                f.arg = Argument::from_chars_spanned(chars)?;
+                if options.field_paths {
+                    f.arg = Argument::path_from_chars(f.arg, chars)?;
+                }
+            }
+            Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+            None => return Err(ParseError::unexpected_end(chars)),
         }
-        while let Some((i, c)) = chars.next() {
-            match c {
-                '}' => return Ok(f),
-                ':' => f.spec = Some(FormatSpec::from_chars(chars)?),
-                _ => return Err(format!("unexpected char '{}' at position {}", c, i)),
+        take_while(chars, char::is_whitespace);
+        if consume(chars, ':') {
+            let start = chars.offset();
+            let spec = FormatSpec::from_chars(chars, options)?;
+            f.spec = Some(Spanned::new(spec, Span::new(start, chars.offset())));
+            take_while(chars, char::is_whitespace);
+        }
+        match chars.next() {
+            Some((_, '}')) => Ok(f),
+            Some((i, c)) => Err(ParseError::unexpected_char(i, c)),
+            None => Err(ParseError::unexpected_end(chars)),
+        }
+    }
+
+    pub fn arg_span(&self) -> Span {
+        self.arg.span()
+    }
+
+    pub fn spec_span(&self) -> Option<Span> {
+        self.spec.as_ref().map(|s| s.span())
+    }
+
+    fn normalize(&mut self, next: &mut usize) {
+        if let Some(ref mut spec) = self.spec {
+            if let Some(ref mut precision) = spec.precision {
+                if let Precision::Star = **precision {
+                    let span = precision.span();
+                    let arg = Spanned::new(Argument::Index(*next), span);
+                    **precision = Precision::Count(Spanned::new(Count::Argument(arg), span));
+                    *next += 1;
+                }
+            }
+            if let Some(ref mut fill_align) = spec.fill_align {
+                if fill_align.fill == Some(' ') {
+                    fill_align.fill = None;
+                }
             }
         }
-        Err(format!("unexpected end of input"))
+        if let Argument::Next = *self.arg {
+            *self.arg = Argument::Index(*next);
+            *next += 1;
+        }
+        if self.spec.as_ref().is_some_and(|s| s.is_empty()) {
+            self.spec = None;
+        }
     }
 }
 
-impl std::fmt::Display for Format {
+impl<'a> std::fmt::Display for Format<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{{")?;
         std::fmt::Display::fmt(&self.arg, f)?;
-        if let Some(ref spec) = self.spec {
+        if let Some(spec) = self.spec.as_ref().filter(|s| !s.is_empty()) {
             write!(f, ":")?;
             std::fmt::Display::fmt(spec, f)?;
         }
@@ -42,70 +369,177 @@ impl std::fmt::Display for Format {
 }
 
 
-#[derive(Debug, Clone)]
-pub enum Argument {
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Argument<'a> {
     Next,
     Index(usize),
-    Name(String),
+    Name(Cow<'a, str>),
+    /// Field and index lookups into an argument, as in `{order.lines[2].sku}`. The first
+    /// segment is the argument itself, a `Field` for a named argument and an `Index` for a
+    /// positional one. Only parsed with `ParseOptions::with_field_paths`.
+    Path(Box<[PathSegment<'a>]>),
 }
 
-impl Argument {
-    fn from_chars(chars: &mut CharIndices) -> Result<Argument, String> {
+impl<'a> Argument<'a> {
+    pub fn into_owned(self) -> Argument<'static> {
+        match self {
+            Argument::Next => Argument::Next,
+            Argument::Index(index) => Argument::Index(index),
+            Argument::Name(name) => Argument::Name(Cow::Owned(name.into_owned())),
+            Argument::Path(path) => Argument::Path(path.into_vec().into_iter().map(PathSegment::into_owned).collect()),
+        }
+    }
+
+    fn from_chars_spanned(chars: &mut CharIndices<'a>) -> Result<Spanned<Argument<'a>>, ParseError> {
+        let start = chars.offset();
+        let arg = Argument::from_chars(chars)?;
+        Ok(Spanned::new(arg, Span::new(start, chars.offset())))
+    }
+
+    fn from_chars(chars: &mut CharIndices<'a>) -> Result<Argument<'a>, ParseError> {
         let off = chars.offset();
-        let str = chars.as_str();
-        let mut prev = chars.clone();
-        let mut arg = Argument::Next;
-        while let Some((_, c)) = chars.next() {
-            match c {
-                c if c.is_ascii_digit() => {
-                    match arg {
-                        Argument::Next => arg = Argument::Index(0),
-                        _ => {},
+        match peek(chars) {
+            Some(c) if c.is_ascii_digit() => {
+                let value = take_while(chars, |c| c.is_ascii_digit());
+                let index = value.parse::<u16>().map_err(|err| ParseError::InvalidInteger {
+                    value: value.to_string(),
+                    span: Span::new(off, chars.offset()),
+                    err,
+                })?;
+                Ok(Argument::Index(index as usize))
+            }
+            Some(c) if is_ident_start(c) => {
+                let raw = chars.as_str().starts_with("r#");
+                if raw {
+                    chars.advance_by(2).unwrap();
+                    match chars.clone().next() {
+                        Some((_, c)) if is_ident_start(c) => {},
+                        Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+                        None => return Err(ParseError::unexpected_end(chars)),
                     }
                 }
-                c if c == '_' || c.is_ascii_alphabetic() => {
-                    match arg {
-                        Argument::Next => arg = Argument::Name(String::new()),
-                        Argument::Index(_) => break,
-                        Argument::Name(_) => {},
-                    }
+                let name = take_while(chars, is_ident_continue);
+                if name == "_" {
+                    return Err(ParseError::InvalidName {
+                        value: name.to_string(),
+                        span: Span::new(off, chars.offset()),
+                    });
                 }
-                _ => break,
+                Ok(Argument::Name(Cow::Borrowed(name)))
             }
-            prev = chars.clone();
+            _ => Ok(Argument::Next),
         }
-        *chars = prev;
-        match arg {
-            Argument::Next => {},
-            Argument::Index(ref mut index) => {
-                *index = str[.. chars.offset() - off].parse::<usize>().map_err(|e| e.to_string())?;
-            }
-            Argument::Name(ref mut name) => {
-                name.push_str(&str[.. chars.offset() - off]);
+    }
+
+    fn path_from_chars(arg: Spanned<Argument<'a>>, chars: &mut CharIndices<'a>) -> Result<Spanned<Argument<'a>>, ParseError> {
+        let mut path = match *arg {
+            Argument::Index(index) => vec![PathSegment::Index(index)],
+            Argument::Name(ref name) => vec![PathSegment::Field(name.clone())],
+            _ => return Ok(arg),
+        };
+        loop {
+            let off = chars.offset();
+            if consume(chars, '.') {
+                match chars.clone().next() {
+                    Some((_, c)) if is_ident_start(c) => {}
+                    Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+                    None => return Err(ParseError::unexpected_end(chars)),
+                }
+                match Argument::from_chars(chars)? {
+                    Argument::Name(name) => path.push(PathSegment::Field(name)),
+                    _ => unreachable!(),
+                }
+            } else if consume(chars, '[') {
+                let value = take_while(chars, |c| c.is_ascii_digit());
+                let span = Span::new(off + 1, chars.offset());
+                match chars.next() {
+                    Some((_, ']')) if !value.is_empty() => {}
+                    Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+                    None => return Err(ParseError::unexpected_end(chars)),
+                }
+                let index = value.parse::<usize>().map_err(|err| ParseError::InvalidInteger {
+                    value: value.to_string(),
+                    span,
+                    err,
+                })?;
+                path.push(PathSegment::Index(index));
+            } else {
+                break;
             }
         }
-        return Ok(arg);
+        if path.len() == 1 {
+            return Ok(arg);
+        }
+        Ok(Spanned::new(Argument::Path(path.into()), Span::new(arg.span().start, chars.offset())))
     }
 }
 
-impl std::fmt::Display for Argument {
+impl<'a> std::fmt::Display for Argument<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             Argument::Next => Ok(()),
             Argument::Index(index) => write!(f, "{}", index),
             Argument::Name(ref name) => write!(f, "{}", name),
+            Argument::Path(ref path) => {
+                for (i, segment) in path.iter().enumerate() {
+                    match *segment {
+                        PathSegment::Field(ref name) if i == 0 => write!(f, "{}", name)?,
+                        PathSegment::Field(ref name) => write!(f, ".{}", name)?,
+                        PathSegment::Index(index) if i == 0 => write!(f, "{}", index)?,
+                        PathSegment::Index(index) => write!(f, "[{}]", index)?,
+                    }
+                }
+                Ok(())
+            }
         }
     }
 }
 
-impl Default for Argument {
+impl<'a> Default for Argument<'a> {
     fn default() -> Self {
         Argument::Next
     }
 }
 
+impl<'a> From<usize> for Argument<'a> {
+    fn from(index: usize) -> Self {
+        Argument::Index(index)
+    }
+}
+
+impl<'a> From<&'a str> for Argument<'a> {
+    fn from(name: &'a str) -> Self {
+        Argument::Name(Cow::Borrowed(name))
+    }
+}
+
+impl<'a> From<String> for Argument<'a> {
+    fn from(name: String) -> Self {
+        Argument::Name(Cow::Owned(name))
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PathSegment<'a> {
+    Field(Cow<'a, str>),
+    Index(usize),
+}
+
+impl<'a> PathSegment<'a> {
+    pub fn into_owned(self) -> PathSegment<'static> {
+        match self {
+            PathSegment::Field(name) => PathSegment::Field(Cow::Owned(name.into_owned())),
+            PathSegment::Index(index) => PathSegment::Index(index),
+        }
+    }
+}
+
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Align {
     Left,
     Center,
@@ -136,40 +570,54 @@ impl TryFrom<char> for Align {
 }
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FillAlign {
     fill: Option<char>,
     align: Align,
 }
 
 impl FillAlign {
-    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<FillAlign>, String> {
-        let prev = chars.clone();
-        let mut step = 0;
-        let mut fill = None;
-        while let Some((_, c)) = chars.next() {
-            match c {
-                '^' | '<' | '>' => {
-                    let align = Align::try_from(c).unwrap();
-                    return Ok(Some(FillAlign {
-                        fill,
-                        align,
-                    }));
-                }
-                _ if step == 0 => {
-                    fill = Some(c);
-                    step += 1;
-                }
-                _ => {
-                    *chars = prev;
-                    return Ok(None);
-                }
-            }
+    pub fn new(fill: Option<char>, align: Align) -> FillAlign {
+        FillAlign { fill, align }
+    }
+
+    pub fn fill(&self) -> Option<char> {
+        self.fill
+    }
+
+    pub fn align(&self) -> Align {
+        self.align
+    }
+
+    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<FillAlign>, ParseError> {
+        let mut ahead = chars.clone();
+        let first = ahead.next().map(|(_, c)| c);
+        let second = ahead.next().map(|(_, c)| c);
+        if let Some(align) = second.and_then(|c| Align::try_from(c).ok()) {
+            chars.advance_by(2).unwrap();
+            return Ok(Some(FillAlign {
+                fill: first,
+                align,
+            }));
+        }
+        if let Some(align) = first.and_then(|c| Align::try_from(c).ok()) {
+            chars.next();
+            return Ok(Some(FillAlign {
+                fill: None,
+                align,
+            }));
         }
         Ok(None)
     }
 }
 
+impl From<Align> for FillAlign {
+    fn from(align: Align) -> Self {
+        FillAlign::new(None, align)
+    }
+}
+
 impl std::fmt::Display for FillAlign {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if let Some(c) = self.fill {
@@ -180,14 +628,15 @@ impl std::fmt::Display for FillAlign {
 }
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Sign {
     Plus,
     Minus,
 }
 
 impl Sign {
-    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<Sign>, String> {
+    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<Sign>, ParseError> {
         let prev = chars.clone();
         if let Some((_, c)) = chars.next() {
             return match Sign::try_from(c) {
@@ -224,21 +673,29 @@ impl TryFrom<char> for Sign {
 }
 
 
-#[derive(Debug, Clone)]
-pub enum Precision {
-    Count(Count),
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Precision<'a> {
+    Count(Spanned<Count<'a>>),
     Star,
 }
 
-impl Precision {
-    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<Precision>, String> {
+impl<'a> Precision<'a> {
+    pub fn into_owned(self) -> Precision<'static> {
+        match self {
+            Precision::Count(count) => Precision::Count(count.map(Count::into_owned)),
+            Precision::Star => Precision::Star,
+        }
+    }
+
+    fn from_chars_opt(chars: &mut CharIndices<'a>) -> Result<Option<Precision<'a>>, ParseError> {
         let prev = chars.clone();
         if let Some((_, c)) = chars.next() {
             return match c {
                 '*' => Ok(Some(Precision::Star)),
                 _ => {
                     *chars = prev;
-                    match Count::from_chars_opt(chars)? {
+                    match Count::from_chars_spanned_opt(chars)? {
                         Some(count) => Ok(Some(Precision::Count(count))),
                         None => Ok(None),
                     }
@@ -249,7 +706,19 @@ impl Precision {
     }
 }
 
-impl std::fmt::Display for Precision {
+impl<'a> From<Count<'a>> for Precision<'a> {
+    fn from(count: Count<'a>) -> Self {
+        Precision::Count(count.into())
+    }
+}
+
+impl<'a> From<usize> for Precision<'a> {
+    fn from(value: usize) -> Self {
+        Precision::Count(Count::Value(value).into())
+    }
+}
+
+impl<'a> std::fmt::Display for Precision<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             Precision::Count(ref count) => write!(f, ".{}", count),
@@ -259,33 +728,46 @@ impl std::fmt::Display for Precision {
 }
 
 
-#[derive(Debug, Clone)]
-pub enum Count {
-    Argument(Argument),
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Count<'a> {
+    Argument(Spanned<Argument<'a>>),
     Value(usize),
 }
 
-impl Count {
-    fn from_chars_opt(chars: &mut CharIndices) -> Result<Option<Count>, String> {
-        let prev = chars.clone();
-        let arg = Argument::from_chars(chars)?;
+impl<'a> Count<'a> {
+    pub fn into_owned(self) -> Count<'static> {
+        match self {
+            Count::Argument(arg) => Count::Argument(arg.map(Argument::into_owned)),
+            Count::Value(value) => Count::Value(value),
+        }
+    }
 
-        let p = chars.clone();
-        let arg_suffix = if let Some((_, '$')) = chars.next() {
-            true
-        } else {
-            *chars = p;
-            false
-        };
+    fn from_chars_spanned_opt(chars: &mut CharIndices<'a>) -> Result<Option<Spanned<Count<'a>>>, ParseError> {
+        let start = chars.offset();
+        let count = Count::from_chars_opt(chars)?;
+        Ok(count.map(|c| Spanned::new(c, Span::new(start, chars.offset()))))
+    }
+
+    fn from_chars_opt(chars: &mut CharIndices<'a>) -> Result<Option<Count<'a>>, ParseError> {
+        if chars.as_str().starts_with("r#") {
+            return Err(ParseError::unexpected_char(chars.offset() + 1, '#'));
+        }
+        let prev = chars.clone();
+        let arg = Argument::from_chars_spanned(chars)?;
+        if let Argument::Next = *arg {
+            return Ok(None);
+        }
+        let arg_suffix = consume(chars, '$');
 
-        match arg {
+        match *arg {
             Argument::Next => Ok(None),
             Argument::Index(index) => if arg_suffix {
                 Ok(Some(Count::Argument(arg)))
             } else {
                 Ok(Some(Count::Value(index)))
             }
-            Argument::Name(_) => if arg_suffix {
+            Argument::Name(_) | Argument::Path(_) => if arg_suffix {
                 Ok(Some(Count::Argument(arg)))
             } else {
                 *chars = prev;
@@ -295,7 +777,13 @@ impl Count {
     }
 }
 
-impl std::fmt::Display for Count {
+impl<'a> From<usize> for Count<'a> {
+    fn from(value: usize) -> Self {
+        Count::Value(value)
+    }
+}
+
+impl<'a> std::fmt::Display for Count<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
             Count::Argument(ref arg) => write!(f, "{}$", arg),
@@ -305,8 +793,10 @@ impl std::fmt::Display for Count {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum FormatType {
+    #[default]
     Display,
     Debug,
     DebugLowerHex,
@@ -318,23 +808,42 @@ pub enum FormatType {
     Binary,
     LowerExp,
     UpperExp,
+    Custom(String),
 }
 
 impl FormatType {
-    fn from_chars(chars: &mut CharIndices) -> Result<FormatType, String> {
-        let mut prev = chars.clone();
+    fn from_chars(chars: &mut CharIndices, options: &ParseOptions) -> Result<FormatType, ParseError> {
         let str = chars.as_str();
         let off = chars.offset();
-        while let Some((_, c)) = chars.next() {
-            if c == '}' {
-                *chars = prev;
-                break;
-            } else {
-                prev = chars.clone();
+        match peek(chars) {
+            Some('?') => {
+                chars.next();
+            }
+            Some(c) if is_ident_start(c) => {
+                let start = chars.clone();
+                let name = take_while(chars, is_ident_continue);
+                if (c == 'x' || c == 'X') && !options.custom_types.iter().any(|t| t == name) {
+                    *chars = start;
+                    chars.next();
+                    consume(chars, '?');
+                } else if name == "_" {
+                    return Err(ParseError::InvalidName {
+                        value: name.to_string(),
+                        span: Span::new(off, chars.offset()),
+                    });
+                }
             }
+            _ => {}
         }
         let s = &str[.. chars.offset() - off];
-        FormatType::from_str(s).map_err(|_| format!("unrecognized value type: '{}'", s))
+        match FormatType::from_str(s) {
+            Ok(t) => Ok(t),
+            Err(_) if options.custom_types.iter().any(|t| t == s) => Ok(FormatType::Custom(s.to_string())),
+            Err(_) => Err(ParseError::UnknownFormatType {
+                value: s.to_string(),
+                span: Span::new(off, chars.offset()),
+            }),
+        }
     }
 }
 
@@ -352,6 +861,7 @@ impl std::fmt::Display for FormatType {
             FormatType::Binary => write!(f, "b"),
             FormatType::LowerExp => write!(f, "e"),
             FormatType::UpperExp => write!(f, "E"),
+            FormatType::Custom(ref name) => write!(f, "{}", name),
         }
     }
 }
@@ -378,79 +888,165 @@ impl FromStr for FormatType {
 }
 
 
-#[derive(Debug, Clone)]
-pub struct FormatSpec {
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FormatSpec<'a> {
     fill_align: Option<FillAlign>,
     sign: Option<Sign>,
     alter: bool,
     zero: bool,
-    width: Option<Count>,
-    precision: Option<Precision>,
+    width: Option<Spanned<Count<'a>>>,
+    precision: Option<Spanned<Precision<'a>>>,
     format_type: FormatType,
 }
 
-impl FormatSpec {
-    fn from_chars(chars: &mut CharIndices) -> Result<FormatSpec, String> {
-        let mut prev = chars.clone();
-        let mut spec = FormatSpec {
-            fill_align: FillAlign::from_chars_opt(chars)?,
-            sign: Sign::from_chars_opt(chars)?,
-            alter: false,
-            zero: false,
-            width: None,
-            precision: None,
-            format_type: FormatType::Display,
-        };
-        let mut step = 0;
-        while let Some((i, c)) = chars.next() {
-            match c {
-                '}' => {
-                    *chars = prev;
-                    return Ok(spec);
-                }
-                '#' if step == 0 => {
-                    spec.alter = true;
-                    step += 1;
-                }
-                '0' if step < 2 => {
-                    spec.zero = true;
-                    step = 2;
-                }
-                _ if step < 3 => {
-                    *chars = prev;
-                    spec.width = Count::from_chars_opt(chars)?;
-                    step = 3;
-                }
-                '.' if step < 4 => {
-                    spec.precision = Precision::from_chars_opt(chars)?;
-                    step = 4;
-                }
-                _ if step < 5 => {
-                    *chars = prev;
-                    spec.format_type = FormatType::from_chars(chars)?;
-                    step = 5;
-                }
-                _ => return Err(format!("unexpected char '{}' at position {}", c, i))
-            }
-            prev = chars.clone();
-        }
-        Err(format!("unexpected end of input"))
+impl<'a> FormatSpec<'a> {
+    pub fn new() -> FormatSpec<'a> {
+        FormatSpec::default()
     }
-}
 
-impl std::fmt::Display for FormatSpec {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if let Some(a) = self.fill_align {
-            write!(f, "{}", a)?;
-        }
-        if let Some(s) = self.sign {
-            write!(f, "{}", s)?;
-        }
-        if self.alter {
-            write!(f, "#")?;
-        }
-        if self.zero {
-            write!(f, "0")?;
+    pub fn with_fill_align<A: Into<FillAlign>>(mut self, fill_align: A) -> FormatSpec<'a> {
+        self.fill_align = Some(fill_align.into());
+        self
+    }
+
+    pub fn with_sign(mut self, sign: Sign) -> FormatSpec<'a> {
+        self.sign = Some(sign);
+        self
+    }
+
+    pub fn with_alter(mut self, alter: bool) -> FormatSpec<'a> {
+        self.alter = alter;
+        self
+    }
+
+    pub fn with_zero(mut self, zero: bool) -> FormatSpec<'a> {
+        self.zero = zero;
+        self
+    }
+
+    pub fn with_width<C: Into<Count<'a>>>(mut self, width: C) -> FormatSpec<'a> {
+        self.width = Some(width.into().into());
+        self
+    }
+
+    pub fn with_precision<P: Into<Precision<'a>>>(mut self, precision: P) -> FormatSpec<'a> {
+        self.precision = Some(precision.into().into());
+        self
+    }
+
+    pub fn with_spanned_width(mut self, width: Spanned<Count<'a>>) -> FormatSpec<'a> {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn with_spanned_precision(mut self, precision: Spanned<Precision<'a>>) -> FormatSpec<'a> {
+        self.precision = Some(precision);
+        self
+    }
+
+    pub fn with_format_type(mut self, format_type: FormatType) -> FormatSpec<'a> {
+        self.format_type = format_type;
+        self
+    }
+
+    pub fn fill_align(&self) -> Option<FillAlign> {
+        self.fill_align
+    }
+
+    pub fn sign(&self) -> Option<Sign> {
+        self.sign
+    }
+
+    pub fn alter(&self) -> bool {
+        self.alter
+    }
+
+    pub fn zero(&self) -> bool {
+        self.zero
+    }
+
+    pub fn width(&self) -> Option<&Spanned<Count<'a>>> {
+        self.width.as_ref()
+    }
+
+    pub fn precision(&self) -> Option<&Spanned<Precision<'a>>> {
+        self.precision.as_ref()
+    }
+
+    pub fn format_type(&self) -> &FormatType {
+        &self.format_type
+    }
+
+    pub fn into_owned(self) -> FormatSpec<'static> {
+        FormatSpec {
+            fill_align: self.fill_align,
+            sign: self.sign,
+            alter: self.alter,
+            zero: self.zero,
+            width: self.width.map(|w| w.map(Count::into_owned)),
+            precision: self.precision.map(|p| p.map(Precision::into_owned)),
+            format_type: self.format_type,
+        }
+    }
+
+    fn from_chars(chars: &mut CharIndices<'a>, options: &ParseOptions) -> Result<FormatSpec<'a>, ParseError> {
+        let mut spec = FormatSpec {
+            fill_align: FillAlign::from_chars_opt(chars)?,
+            sign: Sign::from_chars_opt(chars)?,
+            alter: consume(chars, '#'),
+            zero: false,
+            width: None,
+            precision: None,
+            format_type: FormatType::Display,
+        };
+        // "0$" is a width taken from argument 0, not a zero flag followed by a stray '$'
+        if !chars.as_str().starts_with("0$") {
+            spec.zero = consume(chars, '0');
+        }
+        spec.width = Count::from_chars_spanned_opt(chars)?;
+        let start = chars.offset();
+        if consume(chars, '.') {
+            spec.precision = Precision::from_chars_opt(chars)?
+                .map(|p| Spanned::new(p, Span::new(start, chars.offset())));
+        }
+        spec.format_type = FormatType::from_chars(chars, options)?;
+        Ok(spec)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fill_align.is_none()
+            && self.sign.is_none()
+            && !self.alter
+            && !self.zero
+            && self.width.is_none()
+            && self.precision.is_none()
+            && matches!(self.format_type, FormatType::Display)
+    }
+
+    pub fn width_span(&self) -> Option<Span> {
+        self.width.as_ref().map(|w| w.span())
+    }
+
+    pub fn precision_span(&self) -> Option<Span> {
+        self.precision.as_ref().map(|p| p.span())
+    }
+}
+
+impl<'a> std::fmt::Display for FormatSpec<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(a) = self.fill_align {
+            write!(f, "{}", a)?;
+        }
+        if let Some(s) = self.sign {
+            write!(f, "{}", s)?;
+        }
+        if self.alter {
+            write!(f, "#")?;
+        }
+        if self.zero {
+            write!(f, "0")?;
         }
         if let Some(ref w) = self.width {
             write!(f, "{}", w)?;
@@ -463,107 +1059,357 @@ impl std::fmt::Display for FormatSpec {
 }
 
 
-#[derive(Debug, Clone)]
-pub enum FormatStringItem {
-    Text(String),
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FormatStringItem<'a> {
+    Text(Cow<'a, str>),
     Escape(char),
-    Format(Format),
+    Format(Format<'a>),
+    Error(Cow<'a, str>),
+}
+
+impl<'a> FormatStringItem<'a> {
+    pub fn into_owned(self) -> FormatStringItem<'static> {
+        match self {
+            FormatStringItem::Text(text) => FormatStringItem::Text(Cow::Owned(text.into_owned())),
+            FormatStringItem::Escape(c) => FormatStringItem::Escape(c),
+            FormatStringItem::Format(fmt) => FormatStringItem::Format(fmt.into_owned()),
+            FormatStringItem::Error(text) => FormatStringItem::Error(Cow::Owned(text.into_owned())),
+        }
+    }
 }
 
-impl std::fmt::Display for FormatStringItem {
+impl<'a> std::fmt::Display for FormatStringItem<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match *self {
-            FormatStringItem::Text(ref s) => write!(f, "{}", s),
+            FormatStringItem::Text(ref s) => {
+                let mut rest = s.as_ref();
+                while let Some(pos) = rest.find(['{', '}']) {
+                    let brace = &rest[pos..pos + 1];
+                    write!(f, "{}{}{}", &rest[..pos], brace, brace)?;
+                    rest = &rest[pos + 1..];
+                }
+                write!(f, "{}", rest)
+            }
             FormatStringItem::Escape(c) => write!(f, "{}{}", c, c),
             FormatStringItem::Format(ref fmt) => write!(f, "{}", fmt),
+            FormatStringItem::Error(ref s) => write!(f, "{}", s),
         }
     }
 }
 
 
+pub fn escape_literal(text: &str) -> Cow<'_, str> {
+    if text.contains(['{', '}']) {
+        let mut s = String::with_capacity(text.len() + 4);
+        for c in text.chars() {
+            if c == '{' || c == '}' {
+                s.push(c);
+            }
+            s.push(c);
+        }
+        Cow::Owned(s)
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    custom_types: Vec<String>,
+    field_paths: bool,
+}
+
+static DEFAULT_OPTIONS: ParseOptions = ParseOptions::new();
+
+impl ParseOptions {
+    pub const fn new() -> ParseOptions {
+        ParseOptions {
+            custom_types: Vec::new(),
+            field_paths: false,
+        }
+    }
+
+    /// Accepts `name` as a format type, parsed into `FormatType::Custom`. Custom types must be
+    /// identifiers; the types known to `format_args!` take precedence.
+    pub fn with_custom_type<S: Into<String>>(mut self, name: S) -> ParseOptions {
+        self.custom_types.push(name.into());
+        self
+    }
+
+    pub fn custom_types(&self) -> &[String] {
+        &self.custom_types
+    }
+
+    /// Accepts field and index lookups in arguments, like `{user.name}` or `{items[0]}`,
+    /// parsed into `Argument::Path`.
+    pub fn with_field_paths(mut self, field_paths: bool) -> ParseOptions {
+        self.field_paths = field_paths;
+        self
+    }
+
+    pub fn field_paths(&self) -> bool {
+        self.field_paths
+    }
+}
+
+
+pub fn parse_iter(fmt_str: &str) -> ParseIter<'_, 'static> {
+    parse_iter_with(fmt_str, &DEFAULT_OPTIONS)
+}
+
+pub fn parse_iter_with<'a, 'o>(fmt_str: &'a str, options: &'o ParseOptions) -> ParseIter<'a, 'o> {
+    ParseIter {
+        input: fmt_str,
+        chars: fmt_str.char_indices(),
+        options,
+        done: false,
+    }
+}
+
+
 #[derive(Debug, Clone)]
-pub struct FormatString(Vec<FormatStringItem>);
+pub struct ParseIter<'a, 'o> {
+    input: &'a str,
+    chars: CharIndices<'a>,
+    options: &'o ParseOptions,
+    done: bool,
+}
 
-impl FormatString {
-    pub fn parse(fmt_str: &str) -> Result<FormatString, String> {
-        let mut chars = fmt_str.char_indices();
-        Self::from_chars(&mut chars)
+impl<'a, 'o> ParseIter<'a, 'o> {
+    fn text(&self, start: usize, end: usize) -> Spanned<FormatStringItem<'a>> {
+        Spanned::new(FormatStringItem::Text(Cow::Borrowed(&self.input[start..end])), Span::new(start, end))
     }
 
-    fn from_chars(chars: &mut CharIndices) -> Result<FormatString, String> {
-        let mut items = Vec::new();
-        let mut s = String::new();
-        let mut prev = chars.clone();
-        while let Some((i, c)) = chars.next() {
+    fn next_item(&mut self) -> Result<Option<Spanned<FormatStringItem<'a>>>, ParseError> {
+        let start = self.chars.offset();
+        let mut prev = self.chars.clone();
+        while let Some((i, c)) = self.chars.next() {
             match c {
+                '{' | '}' if start < i => {
+                    self.chars = prev;
+                    return Ok(Some(self.text(start, i)));
+                }
                 '{' => {
-                    if !s.is_empty() {
-                        items.push(FormatStringItem::Text(s));
-                        s = String::new();
-                    }
-                    if chars.as_str().starts_with('{') {
-                        chars.next();
-                        items.push(FormatStringItem::Escape('{'));
+                    let item = if consume(&mut self.chars, '{') {
+                        FormatStringItem::Escape('{')
                     } else {
-                        *chars = prev;
-                        let f = Format::from_chars(chars)?;
-                        items.push(FormatStringItem::Format(f));
-                    }
+                        self.chars = prev;
+                        FormatStringItem::Format(Format::from_chars(&mut self.chars, self.options)?)
+                    };
+                    return Ok(Some(Spanned::new(item, Span::new(i, self.chars.offset()))));
                 }
                 '}' => {
-                    if chars.as_str().starts_with('}') {
-                        if !s.is_empty() {
-                            items.push(FormatStringItem::Text(s));
-                            s = String::new();
-                        }
-                        items.push(FormatStringItem::Escape('}'));
-                        chars.next();
+                    if consume(&mut self.chars, '}') {
+                        return Ok(Some(Spanned::new(FormatStringItem::Escape('}'), Span::new(i, self.chars.offset()))));
                     } else {
-                        return Err(format!("unescaped '{{' at position {}", i));
+                        return Err(ParseError::UnescapedBrace { c, span: Span::of_char(i, c) });
                     }
                 }
-                _ => s.push(c),
+                _ => {}
             }
-            prev = chars.clone();
+            prev = self.chars.clone();
         }
-        if !s.is_empty() {
-            items.push(FormatStringItem::Text(s));
+        if start < self.chars.offset() {
+            Ok(Some(self.text(start, self.chars.offset())))
+        } else {
+            Ok(None)
         }
-        Ok(FormatString(items))
     }
 
-    pub fn items(&self) -> &[FormatStringItem] {
+    fn next_item_recovering(&mut self, errors: &mut Vec<ParseError>) -> Option<Spanned<FormatStringItem<'a>>> {
+        let mut chars = self.chars.clone();
+        let start = chars.offset();
+        match self.next_item() {
+            Ok(item) => item,
+            Err(err) => {
+                let end = if self.input[start..].starts_with('}') {
+                    start + 1
+                } else {
+                    let pos = err.pos().max(start + 1);
+                    match self.input[pos..].find(['{', '}']) {
+                        Some(i) if self.input[pos + i..].starts_with('}') => pos + i + 1,
+                        Some(i) => pos + i,
+                        None => self.input.len(),
+                    }
+                };
+                while chars.offset() < end && chars.next().is_some() {}
+                self.chars = chars;
+                errors.push(err);
+                Some(Spanned::new(FormatStringItem::Error(Cow::Borrowed(&self.input[start..end])), Span::new(start, end)))
+            }
+        }
+    }
+}
+
+impl<'a, 'o> Iterator for ParseIter<'a, 'o> {
+    type Item = Result<Spanned<FormatStringItem<'a>>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_item() {
+            Ok(Some(item)) => Some(Ok(item)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<'a, 'o> std::iter::FusedIterator for ParseIter<'a, 'o> {}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct FormatString<'a>(Vec<Spanned<FormatStringItem<'a>>>);
+
+impl<'a> FormatString<'a> {
+    pub fn new() -> FormatString<'a> {
+        FormatString::default()
+    }
+
+    pub fn builder() -> FormatStringBuilder<'a> {
+        FormatStringBuilder::new()
+    }
+
+    /// Parses `fmt_str` using the grammar of `format_args!`. Any string accepted by the compiler
+    /// is accepted here and vice versa, with one extension: the argument name of a placeholder (but
+    /// not of its width or precision) may be written as a raw identifier (`{r#type}`), which is
+    /// stored and displayed without the `r#` prefix.
+    /// See `tests/data/format_args.txt` for the corpus this is checked against.
+    pub fn parse(fmt_str: &'a str) -> Result<FormatString<'a>, ParseError> {
+        FormatString::parse_with(fmt_str, &DEFAULT_OPTIONS)
+    }
+
+    pub fn parse_with(fmt_str: &'a str, options: &ParseOptions) -> Result<FormatString<'a>, ParseError> {
+        parse_iter_with(fmt_str, options).collect::<Result<Vec<_>, _>>().map(FormatString)
+    }
+
+    /// Parses `fmt_str` without stopping at the first error. Every broken placeholder or stray
+    /// brace becomes a `FormatStringItem::Error` holding the source text it covers, and parsing
+    /// resumes after the next `}` (or before the next `{`). All errors are returned in order.
+    pub fn parse_recovering(fmt_str: &'a str) -> (FormatString<'a>, Vec<ParseError>) {
+        FormatString::parse_recovering_with(fmt_str, &DEFAULT_OPTIONS)
+    }
+
+    pub fn parse_recovering_with(fmt_str: &'a str, options: &ParseOptions) -> (FormatString<'a>, Vec<ParseError>) {
+        let mut iter = parse_iter_with(fmt_str, options);
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        while let Some(item) = iter.next_item_recovering(&mut errors) {
+            items.push(item);
+        }
+        (FormatString(items), errors)
+    }
+
+    pub fn items(&self) -> &[Spanned<FormatStringItem<'a>>] {
         &self.0
     }
 
-    pub fn each_argument<F>(&self, mut f: F) where F: FnMut(&Argument) -> bool {
-        for item in self.0.iter() {
-            if let FormatStringItem::Format(ref fmt) = item {
-                if !f(&fmt.arg) {
-                    break;
+    pub fn into_owned(self) -> FormatString<'static> {
+        FormatString(self.0.into_iter().map(|i| i.map(FormatStringItem::into_owned)).collect())
+    }
+
+    pub fn from_literal<T: Into<Cow<'a, str>>>(text: T) -> FormatString<'a> {
+        let mut f = FormatString::new();
+        f.push_text(text);
+        f
+    }
+
+    pub fn push(&mut self, item: FormatStringItem<'a>) {
+        self.0.push(item.into());
+    }
+
+    /// Appends literal text, turning every `{` and `}` into a `FormatStringItem::Escape`.
+    pub fn push_text<T: Into<Cow<'a, str>>>(&mut self, text: T) {
+        fn slice<'a>(text: &Cow<'a, str>, start: usize, end: usize) -> Cow<'a, str> {
+            match *text {
+                Cow::Borrowed(s) => Cow::Borrowed(&s[start..end]),
+                Cow::Owned(ref s) => Cow::Owned(s[start..end].to_string()),
+            }
+        }
+
+        let text = text.into();
+        if !text.contains(['{', '}']) {
+            if !text.is_empty() {
+                self.push(FormatStringItem::Text(text));
+            }
+            return;
+        }
+        let mut start = 0;
+        while let Some(pos) = text[start..].find(['{', '}']) {
+            if pos > 0 {
+                self.push(FormatStringItem::Text(slice(&text, start, start + pos)));
+            }
+            self.push(FormatStringItem::Escape(text[start + pos..].chars().next().unwrap()));
+            start += pos + 1;
+        }
+        if start < text.len() {
+            self.push(FormatStringItem::Text(slice(&text, start, text.len())));
+        }
+    }
+
+    pub fn normalize(&mut self) {
+        let mut next = 0;
+        let mut items: Vec<Spanned<FormatStringItem<'a>>> = Vec::with_capacity(self.0.len());
+        for mut item in self.0.drain(..) {
+            let literal = match *item {
+                FormatStringItem::Text(ref s) => s.clone(),
+                FormatStringItem::Escape(c) => Cow::Owned(c.to_string()),
+                FormatStringItem::Format(ref mut fmt) => {
+                    fmt.normalize(&mut next);
+                    items.push(item);
+                    continue;
                 }
-                if let Some(ref spec) = fmt.spec {
-                    if let Some(ref width) = spec.width {
-                        if let Count::Argument(ref arg) = width {
-                            if !f(arg) {
-                                break;
-                            }
-                        }
-                    }
-                    if let Some(ref prec) = spec.precision {
-                        if let Precision::Count(Count::Argument(ref arg)) = prec {
-                            if !f(arg) {
-                                break;
-                            }
-                        }
-                    }
+                FormatStringItem::Error(_) => {
+                    items.push(item);
+                    continue;
+                }
+            };
+            if let Some(last) = items.last_mut() {
+                if let FormatStringItem::Text(ref mut s) = **last {
+                    s.to_mut().push_str(&literal);
+                    last.span.end = item.span().end;
+                    continue;
                 }
             }
+            items.push(Spanned::new(FormatStringItem::Text(literal), item.span()));
         }
+        self.0 = items;
+    }
+
+    pub fn each_argument<F>(&self, f: F) where F: FnMut(&Spanned<Argument<'a>>) -> bool {
+        struct EachArgument<F> {
+            f: F,
+            done: bool,
+        }
+
+        impl<'a, F: FnMut(&Spanned<Argument<'a>>) -> bool> Visit<'a> for EachArgument<F> {
+            fn visit_argument(&mut self, node: &Spanned<Argument<'a>>) {
+                if !self.done {
+                    self.done = !(self.f)(node);
+                }
+            }
+        }
+
+        EachArgument { f, done: false }.visit_format_string(self);
+    }
+}
+
+impl<'a> From<Vec<Spanned<FormatStringItem<'a>>>> for FormatString<'a> {
+    fn from(items: Vec<Spanned<FormatStringItem<'a>>>) -> Self {
+        FormatString(items)
     }
 }
 
-impl std::fmt::Display for FormatString {
+impl<'a> std::fmt::Display for FormatString<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         for i in self.0.iter() {
             write!(f, "{}", i)?;
@@ -573,6 +1419,76 @@ impl std::fmt::Display for FormatString {
 }
 
 
+#[derive(Debug, Default)]
+pub struct FormatStringBuilder<'a> {
+    fmt_str: FormatString<'a>,
+}
+
+impl<'a> FormatStringBuilder<'a> {
+    pub fn new() -> FormatStringBuilder<'a> {
+        FormatStringBuilder::default()
+    }
+
+    pub fn text<T: Into<Cow<'a, str>>>(mut self, text: T) -> FormatStringBuilder<'a> {
+        self.fmt_str.push_text(text);
+        self
+    }
+
+    pub fn format(mut self, format: Format<'a>) -> FormatStringBuilder<'a> {
+        self.fmt_str.push(FormatStringItem::Format(format));
+        self
+    }
+
+    /// Panics if `arg` is not a valid argument name, or a path with an invalid field name.
+    pub fn arg<A: Into<Argument<'a>>>(self, arg: A) -> FormatStringBuilder<'a> {
+        let arg = arg.into();
+        check_argument(&arg);
+        self.format(Format::new(arg))
+    }
+
+    pub fn next(self) -> FormatStringBuilder<'a> {
+        self.format(Format::new(Argument::Next))
+    }
+
+    /// Sets the spec of the argument added last. Panics if the last item is not an argument,
+    /// or if the width or precision argument is not a valid argument name.
+    pub fn spec(mut self, spec: FormatSpec<'a>) -> FormatStringBuilder<'a> {
+        let precision = match spec.precision.as_ref().map(|p| p.node()) {
+            Some(Precision::Count(c)) => Some(c),
+            _ => None,
+        };
+        for count in spec.width.iter().chain(precision) {
+            if let Count::Argument(ref arg) = **count {
+                check_argument(arg);
+            }
+        }
+        match self.fmt_str.0.last_mut().map(|i| &mut i.node) {
+            Some(FormatStringItem::Format(ref mut f)) => f.spec = Some(spec.into()),
+            _ => panic!("spec(...) must follow an argument"),
+        }
+        self
+    }
+
+    pub fn build(self) -> FormatString<'a> {
+        self.fmt_str
+    }
+}
+
+fn check_argument(arg: &Argument) {
+    let names: Vec<&str> = match *arg {
+        Argument::Name(ref name) => vec![name],
+        Argument::Path(ref path) => path.iter().filter_map(|s| match *s {
+            PathSegment::Field(ref name) => Some(name.as_ref()),
+            PathSegment::Index(_) => None,
+        }).collect(),
+        _ => Vec::new(),
+    };
+    for name in names {
+        assert!(is_valid_name(name), "invalid argument name '{}'", name);
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -584,4 +1500,766 @@ mod tests {
         let out = f.to_string();
         assert_eq!(input, out);
     }
+
+    #[test]
+    fn spans() {
+        let f = FormatString::parse("ab {name:#w$.3} {{").unwrap();
+        let spans: Vec<Span> = f.items().iter().map(|i| i.span()).collect();
+        assert_eq!(spans, vec![Span::new(0, 3), Span::new(3, 15), Span::new(15, 16), Span::new(16, 18)]);
+
+        let fmt = match **f.items().get(1).unwrap() {
+            FormatStringItem::Format(ref fmt) => fmt,
+            _ => panic!("format item expected"),
+        };
+        assert_eq!(fmt.arg_span(), Span::new(4, 8));
+        assert_eq!(fmt.spec_span(), Some(Span::new(9, 14)));
+        let spec = fmt.spec.as_ref().unwrap();
+        assert_eq!(spec.width_span(), Some(Span::new(10, 12)));
+        assert_eq!(spec.precision_span(), Some(Span::new(12, 14)));
+        match **spec.width.as_ref().unwrap() {
+            Count::Argument(ref arg) => assert_eq!(arg.span(), Span::new(10, 11)),
+            _ => panic!("width argument expected"),
+        }
+        match **spec.precision.as_ref().unwrap() {
+            Precision::Count(ref count) => assert_eq!(count.span(), Span::new(13, 14)),
+            _ => panic!("precision count expected"),
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let f = FormatString::builder()
+            .text("x = {")
+            .arg("name")
+            .spec(FormatSpec::new()
+                .with_fill_align(FillAlign::new(Some('*'), Align::Center))
+                .with_sign(Sign::Plus)
+                .with_alter(true)
+                .with_width(Count::Argument(Argument::from("w").into()))
+                .with_precision(3)
+                .with_format_type(FormatType::LowerExp))
+            .text("}, ")
+            .next()
+            .arg(1)
+            .spec(FormatSpec::new().with_zero(true).with_width(8).with_format_type(FormatType::Debug))
+            .build();
+        let out = f.to_string();
+        assert_eq!(out, "x = {{{name:*^+#w$.3e}}}, {}{1:08?}");
+
+        let p = FormatString::parse(&out).unwrap();
+        assert_eq!(p.to_string(), out);
+        match **p.items().get(2).unwrap() {
+            FormatStringItem::Format(ref fmt) => {
+                let spec = fmt.spec().unwrap();
+                assert_eq!(spec.fill_align().unwrap().fill(), Some('*'));
+                assert!(spec.alter());
+                assert!(!spec.zero());
+                assert_eq!(spec.precision().unwrap().to_string(), ".3");
+            }
+            _ => panic!("format item expected"),
+        }
+    }
+
+    #[test]
+    fn builder_empty_spec() {
+        let f = FormatString::builder().arg("x").spec(FormatSpec::new()).text("<5").next().spec(FormatSpec::new()).text(">").build();
+        let out = f.to_string();
+        assert_eq!(out, "{x}<5{}>");
+        assert_eq!(FormatString::parse(&out).unwrap().to_string(), out);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid argument name 'a b'")]
+    fn builder_invalid_name() {
+        FormatString::builder().arg("a b");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid argument name '_'")]
+    fn builder_invalid_width_name() {
+        FormatString::builder().arg(0).spec(FormatSpec::new().with_width(Count::Argument(Argument::from("_").into())));
+    }
+
+    #[test]
+    #[should_panic(expected = "spec(...) must follow an argument")]
+    fn builder_spec_without_argument() {
+        FormatString::builder().text("x").spec(FormatSpec::new());
+    }
+
+    #[test]
+    fn unicode_and_raw_names() {
+        let f = FormatString::parse("{größe} {r#type:w$} {_µ1}").unwrap();
+        let mut names = Vec::new();
+        f.each_argument(|arg| {
+            names.push((arg.to_string(), arg.span()));
+            true
+        });
+        assert_eq!(names, vec![
+            ("größe".to_string(), Span::new(1, 8)),
+            ("type".to_string(), Span::new(11, 17)),
+            ("w".to_string(), Span::new(18, 19)),
+            ("_µ1".to_string(), Span::new(23, 27)),
+        ]);
+        assert_eq!(f.to_string(), "{größe} {type:w$} {_µ1}");
+
+        assert!(FormatString::parse("{r#}").is_err());
+        assert!(FormatString::parse("{r#1}").is_err());
+        assert_eq!(FormatString::parse("{:r#a$}").unwrap_err(), ParseError::unexpected_char(3, '#'));
+        assert_eq!(FormatString::parse("{:.r#type$}").unwrap_err(), ParseError::unexpected_char(4, '#'));
+    }
+
+    fn spec(input: &str) -> FormatSpec<'_> {
+        let f = FormatString::parse(input).unwrap();
+        match **f.items().first().unwrap() {
+            FormatStringItem::Format(ref fmt) => fmt.spec().unwrap().node().clone(),
+            _ => panic!("format item expected"),
+        }
+    }
+
+    #[test]
+    fn rustc_spec_edge_cases() {
+        let s = spec("{:0}");
+        assert!(s.zero() && s.width().is_none());
+
+        let s = spec("{:0$}");
+        assert!(!s.zero());
+        assert_eq!(s.width().unwrap().to_string(), "0$");
+
+        let s = spec("{:00$}");
+        assert!(s.zero());
+        assert_eq!(s.width().unwrap().to_string(), "0$");
+
+        let s = spec("{:<<5}");
+        assert_eq!(s.fill_align().unwrap().fill(), Some('<'));
+        assert_eq!(s.width().unwrap().to_string(), "5");
+
+        let s = spec("{:}>5}");
+        assert_eq!(s.fill_align().unwrap().fill(), Some('}'));
+
+        let s = spec("{:-#x?}");
+        assert!(s.sign().is_some() && s.alter());
+        assert_eq!(s.format_type().to_string(), "x?");
+
+        let s = spec("{:5.}");
+        assert!(s.precision().is_none());
+
+        assert_eq!(FormatString::parse("{a :x? }").unwrap().to_string(), "{a:x?}");
+        assert!(FormatString::parse("{:65536}").is_err());
+        assert!(FormatString::parse("{:width}").is_err());
+        assert!(FormatString::parse("{:o?}").is_err());
+        assert!(FormatString::parse("{ 0}").is_err());
+    }
+
+    #[test]
+    fn visitors() {
+        struct Counter {
+            texts: usize,
+            escapes: usize,
+            args: Vec<String>,
+        }
+
+        impl<'a> Visit<'a> for Counter {
+            fn visit_text(&mut self, _text: &str, _span: Span) {
+                self.texts += 1;
+            }
+
+            fn visit_escape(&mut self, _c: char, _span: Span) {
+                self.escapes += 1;
+            }
+
+            fn visit_argument(&mut self, node: &Spanned<Argument<'a>>) {
+                self.args.push(node.to_string());
+            }
+        }
+
+        struct Rename;
+
+        impl<'a> VisitMut<'a> for Rename {
+            fn visit_argument_mut(&mut self, node: &mut Spanned<Argument<'a>>) {
+                if let Argument::Name(ref mut name) = **node {
+                    name.to_mut().make_ascii_uppercase();
+                }
+            }
+
+            fn visit_format_spec_mut(&mut self, node: &mut Spanned<FormatSpec<'a>>) {
+                node.alter = true;
+                visit::visit_format_spec_mut(self, node);
+            }
+        }
+
+        let mut f = FormatString::parse("a {{ {x:w$.p$} b {} }}").unwrap();
+        let mut c = Counter { texts: 0, escapes: 0, args: Vec::new() };
+        c.visit_format_string(&f);
+        assert_eq!(c.texts, 4);
+        assert_eq!(c.escapes, 2);
+        assert_eq!(c.args, vec!["x", "w", "p", ""]);
+
+        Rename.visit_format_string_mut(&mut f);
+        assert_eq!(f.to_string(), "a {{ {X:#W$.P$} b {} }}");
+
+        let mut first = Vec::new();
+        f.each_argument(|arg| {
+            first.push(arg.to_string());
+            first.len() < 2
+        });
+        assert_eq!(first, vec!["X", "W"]);
+    }
+
+    #[test]
+    fn normalize() {
+        let mut f = FormatString::parse("a{{b}} {} {:.*} {:1$}{x:.*?}{:}{: ^5}").unwrap();
+        f.normalize();
+        assert_eq!(f.to_string(), "a{{b}} {0} {2:.1$} {3:1$}{x:.4$?}{5}{6:^5}");
+        assert_eq!(f.items().len(), 9);
+        assert_eq!(f.items()[0].span(), Span::new(0, 7));
+        assert_eq!(FormatString::parse(&f.to_string()).unwrap().to_string(), f.to_string());
+
+        let mut a = FormatString::builder().text("x").text("{").next().build();
+        let mut b = FormatString::parse("x{{{0}").unwrap();
+        a.normalize();
+        b.normalize();
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.items().len(), b.items().len());
+    }
+
+    #[test]
+    fn argument_uses() {
+        let f = FormatString::parse("{} {name:>w$.*x?} {0:1$e} {:p}").unwrap();
+        let uses: Vec<(String, ArgumentRole)> = f.argument_uses().iter()
+            .map(|u| (u.arg().to_string(), u.role()))
+            .collect();
+        assert_eq!(uses, vec![
+            ("0".to_string(), ArgumentRole::Value(FormatTrait::Display)),
+            ("name".to_string(), ArgumentRole::Value(FormatTrait::Debug)),
+            ("w".to_string(), ArgumentRole::Width),
+            ("1".to_string(), ArgumentRole::Precision),
+            ("0".to_string(), ArgumentRole::Value(FormatTrait::LowerExp)),
+            ("1".to_string(), ArgumentRole::Width),
+            ("2".to_string(), ArgumentRole::Value(FormatTrait::Pointer)),
+        ]);
+        assert!(ArgumentRole::Width.is_count());
+        assert_eq!(ArgumentRole::Value(FormatTrait::UpperHex).format_trait().unwrap().to_string(), "UpperHex");
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(escape_literal("a{b}}c"), "a{{b}}}}c");
+        assert!(matches!(escape_literal("plain"), Cow::Borrowed("plain")));
+
+        let user = String::from("{name} }{ done");
+        let f = FormatString::from_literal(user.as_str());
+        assert_eq!(f.to_string(), escape_literal(&user));
+        assert_eq!(f, FormatString::parse(&escape_literal(&user)).unwrap());
+        assert!(matches!(*f.items()[1], FormatStringItem::Text(Cow::Borrowed("name"))));
+        assert!(matches!(*f.items()[2], FormatStringItem::Escape('}')));
+
+        let mut f = FormatString::from_literal("x = ");
+        f.push(FormatStringItem::Format(Format::new("x")));
+        f.push_text(String::from(" {owned}"));
+        f.push_text("");
+        assert_eq!(f.to_string(), "x = {x} {{owned}}");
+        assert_eq!(f.items().len(), 6);
+        assert!(f.items().iter().all(|i| !matches!(**i, FormatStringItem::Text(ref s) if s.contains(['{', '}']))));
+    }
+
+    #[test]
+    fn custom_format_types() {
+        let options = ParseOptions::new().with_custom_type("bytes").with_custom_type("iso").with_custom_type("xml");
+        let input = "{size:bytes} {ts:>20iso} {n:x} {:e} {doc:xml} {n:X?}";
+        let f = FormatString::parse_with(input, &options).unwrap();
+        assert_eq!(f.to_string(), input);
+        let types: Vec<FormatType> = f.items().iter()
+            .filter_map(|i| match **i {
+                FormatStringItem::Format(ref fmt) => Some(fmt.spec().unwrap().format_type().clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(types, vec![
+            FormatType::Custom("bytes".to_string()),
+            FormatType::Custom("iso".to_string()),
+            FormatType::LowerHex,
+            FormatType::LowerExp,
+            FormatType::Custom("xml".to_string()),
+            FormatType::DebugUpperHex,
+        ]);
+        assert_eq!(f.argument_uses()[0].role(), ArgumentRole::Value(FormatTrait::Custom("bytes".to_string())));
+        let bytes = FormatString::parse_with("{x:bytes}", &options).unwrap();
+        assert!(!bytes.is_compatible_with(&FormatString::parse_with("{x:iso}", &options).unwrap()));
+        assert!(bytes.is_compatible_with(&FormatString::parse_with("{x:>8bytes}", &options).unwrap()));
+
+        match FormatString::parse(input) {
+            Err(ParseError::UnknownFormatType { value, span }) => {
+                assert_eq!(value, "bytes");
+                assert_eq!(span, Span::new(6, 11));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(FormatString::parse_with("{x:iso8601}", &options).is_err());
+        assert_eq!(parse_iter_with("{:iso}", &options).count(), 1);
+        let (_, errors) = FormatString::parse_recovering_with("{:bytes} {:kb}", &options);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn equality_and_compatibility() {
+        let a = FormatString::parse("{0} {1:x}").unwrap();
+        let b = FormatString::builder()
+            .arg(0)
+            .text(" ")
+            .arg(1)
+            .spec(FormatSpec::new().with_format_type(FormatType::LowerHex))
+            .build();
+        assert_eq!(a, b);
+        assert_ne!(a, FormatString::parse("{0} {1:X}").unwrap());
+        assert_eq!(FormatString::parse("x{a:>5}").unwrap().items()[1], FormatString::parse("{a:>5}").unwrap().items()[0]);
+
+        let mut c = FormatString::parse("{} {:.*}").unwrap();
+        let mut d = FormatString::parse("{0} {2:.1$}").unwrap();
+        assert_ne!(c, d);
+        c.normalize();
+        d.normalize();
+        assert_eq!(c, d);
+
+        let set: std::collections::HashSet<FormatString> = vec![a, b, c, d].into_iter().collect();
+        assert_eq!(set.len(), 2);
+
+        let compatible = |a, b| FormatString::parse(a).unwrap().is_compatible_with(&FormatString::parse(b).unwrap());
+        assert!(compatible("Hello {name}, you have {count} messages", "{count} Nachrichten für {name}"));
+        assert!(compatible("{0} {1:>w$}", "{1:<w$} {0:5} {0}"));
+        assert!(compatible("{} {}", "{1} {0}"));
+        assert!(compatible("{x:x?}", "{x:#?}"));
+        assert!(!compatible("{} {}", "{}"));
+        assert!(!compatible("{name}", "{name:?}"));
+        assert!(!compatible("{x:x}", "{x:X}"));
+        assert!(!compatible("{0:1$}", "{0:.1$}"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Config {
+            compact: FormatString<'static>,
+            #[serde(with = "tree")]
+            tree: FormatString<'static>,
+        }
+
+        let config = Config {
+            compact: FormatString::parse("a {x:>4} {{").unwrap().into_owned(),
+            tree: FormatString::parse("{0:.*}!").unwrap().into_owned(),
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(json, concat!(r#"{"compact":"a {x:>4} {{","tree":["#,
+            r#"{"Format":{"arg":{"Index":0},"spec":{"fill_align":null,"sign":null,"alter":false,"zero":false,"#,
+            r#""width":null,"precision":"Star","format_type":"Display"}}},{"Text":"!"}]}"#));
+
+        let config: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.compact.to_string(), "a {x:>4} {{");
+        assert_eq!(config.compact.items()[1].span(), Span::new(2, 8));
+        assert_eq!(config.tree.to_string(), "{0:.*}!");
+
+        let config: Config = serde_json::from_str(r#"{"compact":"{}","tree":[{"Text":"{"},{"Format":{"arg":"Next","spec":{"width":{"Value":3}}}}]}"#).unwrap();
+        assert_eq!(config.tree.to_string(), "{{{:3}");
+
+        let err = serde_json::from_str::<FormatString>(r#""{:q}""#).unwrap_err();
+        assert!(err.to_string().starts_with("unrecognized value type: 'q' at position 2"), "{}", err);
+        let err = serde_json::from_str::<Config>(r#"{"compact":"","tree":[{"Format":{"arg":{"Name":"a b"}}}]}"#).unwrap_err();
+        assert!(err.to_string().starts_with("unexpected char 'b' at position 3"), "{}", err);
+
+        use serde::de::DeserializeSeed;
+        let options = ParseOptions::new().with_custom_type("bytes").with_field_paths(true);
+        let f = FormatString::parse_with("{file.size:>8bytes} {0[1]}", &options).unwrap();
+        let json = serde_json::to_string(&f).unwrap();
+        assert!(serde_json::from_str::<FormatString>(&json).is_err());
+        let d = FormatStringSeed(&options).deserialize(&mut serde_json::Deserializer::from_str(&json)).unwrap();
+        assert_eq!(d, f);
+        let json = tree::serialize(&f, serde_json::value::Serializer).unwrap();
+        assert!(tree::deserialize(&json).is_err());
+        assert_eq!(tree::Seed(&options).deserialize(&json).unwrap(), f);
+    }
+
+    #[test]
+    fn borrowed_and_owned() {
+        let input = String::from("ab {name:w$} {{ cd");
+        let f = FormatString::parse(&input).unwrap();
+        match **f.items().first().unwrap() {
+            FormatStringItem::Text(Cow::Borrowed(text)) => assert_eq!(text, "ab "),
+            _ => panic!("borrowed text expected"),
+        }
+        match **f.items().get(1).unwrap() {
+            FormatStringItem::Format(ref fmt) => match **fmt.arg() {
+                Argument::Name(Cow::Borrowed(name)) => assert_eq!(name, "name"),
+                _ => panic!("borrowed name expected"),
+            },
+            _ => panic!("format item expected"),
+        }
+
+        let owned: FormatString<'static> = f.into_owned();
+        drop(input);
+        assert_eq!(owned.to_string(), "ab {name:w$} {{ cd");
+        assert_eq!(owned.items()[1].span(), Span::new(3, 12));
+    }
+
+    #[test]
+    fn parse_iter_stops_at_first_error() {
+        let mut it = parse_iter("ab {x} }} c } {y}");
+        assert_eq!(it.next().unwrap().unwrap().to_string(), "ab ");
+        assert_eq!(it.next().unwrap().unwrap().to_string(), "{x}");
+        assert_eq!(it.next().unwrap().unwrap().to_string(), " ");
+        assert_eq!(it.next().unwrap().unwrap().to_string(), "}}");
+        assert_eq!(it.next().unwrap().unwrap().span(), Span::new(9, 12));
+        match it.next() {
+            Some(Err(ParseError::UnescapedBrace { c: '}', span })) => assert_eq!(span, Span::new(12, 13)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(it.next().is_none());
+        assert!(it.next().is_none());
+        assert_eq!(parse_iter("").count(), 0);
+    }
+
+    #[test]
+    fn parse_recovering() {
+        let input = "a {x:y} b } {ok:>4} {c {d} {e";
+        let (f, errors) = FormatString::parse_recovering(input);
+        assert_eq!(f.to_string(), input);
+        let errs: Vec<(String, Span)> = f.items().iter()
+            .filter_map(|i| match **i {
+                FormatStringItem::Error(ref s) => Some((s.to_string(), i.span())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errs, vec![
+            ("{x:y}".to_string(), Span::new(2, 7)),
+            ("}".to_string(), Span::new(10, 11)),
+            ("{c ".to_string(), Span::new(20, 23)),
+            ("{e".to_string(), Span::new(27, 29)),
+        ]);
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0], ParseError::UnknownFormatType { value: "y".into(), span: Span::new(5, 6) });
+        assert_eq!(errors[3], ParseError::UnexpectedEnd { pos: 29 });
+
+        let mut names = Vec::new();
+        f.each_argument(|a| {
+            names.push(a.to_string());
+            true
+        });
+        assert_eq!(names, vec!["ok", "d"]);
+
+        let (f, errors) = FormatString::parse_recovering("ok {} {{");
+        assert!(errors.is_empty());
+        assert_eq!(f.items().len(), 4);
+    }
+
+    #[test]
+    fn python_dialect() {
+        let py = |s| python::parse(s).unwrap().to_string();
+        assert_eq!(py("{name!r:>10} {} {:{}} {} {{x}}"), "{name:>10?} {} {:2$} {3} {{x}}");
+        assert_eq!(py("{:0=+8.3f}|{:f}|{:<05}|{x:#x}|{y!s:.{p}}"), "{:+08.3}|{:.6}|{:0<5}|{x:#x}|{y:.p$}");
+        assert_eq!(py("{1:{0}} {x} {0}"), "{1:0$} {x} {0}");
+        assert_eq!(py("{x:}<5 {!r:}"), "{x}<5 {:?}");
+        for s in ["{x:}<5", "1{:0}<r9", "[h=1E{1:}^", "_d+{:}<:-x*e", "{0:}>"].iter() {
+            let out = py(s);
+            assert_eq!(FormatString::parse(&out).unwrap().to_string(), out, "{}", s);
+        }
+        assert_eq!(py("{größe:*^6d}|{:o}|{:b}|{!r}"), "{größe:*^6}|{:o}|{:b}|{:?}");
+
+        let unsupported = |s| match python::parse(s) {
+            Err(ParseError::Unsupported { feature, span }) => (feature, span),
+            r => panic!("unexpected result: {:?}", r),
+        };
+        assert_eq!(unsupported("a {0[1]}"), ("index access".to_string(), Span::new(4, 5)));
+        assert_eq!(unsupported("{user.name}"), ("attribute access".to_string(), Span::new(5, 6)));
+        assert_eq!(unsupported("{:,}"), ("grouping option ','".to_string(), Span::new(2, 3)));
+        assert_eq!(unsupported("{x!a}"), ("conversion '!a'".to_string(), Span::new(2, 4)));
+        assert_eq!(unsupported("{:10.2e}"), ("format type 'e'".to_string(), Span::new(6, 7)));
+        assert_eq!(unsupported("{:#X}"), ("'#' with format type 'X'".to_string(), Span::new(3, 4)));
+        assert_eq!(unsupported("{: d}"), ("sign ' '".to_string(), Span::new(2, 3)));
+        assert_eq!(unsupported("{:=5}"), ("'=' alignment".to_string(), Span::new(2, 3)));
+        assert_eq!(unsupported("{a-b}"), ("argument name 'a-b'".to_string(), Span::new(1, 4)));
+
+        let mixed = |s| match python::parse(s) {
+            Err(ParseError::MixedNumbering { span }) => span,
+            r => panic!("unexpected result: {:?}", r),
+        };
+        assert_eq!(mixed("{0} {}"), Span::new(5, 5));
+        assert_eq!(mixed("{} {x} {0}"), Span::new(8, 9));
+        assert_eq!(mixed("{0:{}}"), Span::new(4, 4));
+        assert_eq!(mixed("{:.{1}}"), Span::new(4, 5));
+
+        match python::parse("{:q}") {
+            Err(ParseError::UnknownFormatType { value, .. }) => assert_eq!(value, "q"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match python::parse("x }") {
+            Err(ParseError::UnescapedBrace { span, .. }) => assert_eq!(span, Span::new(2, 3)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn printf_dialect() {
+        let printf = |s| printf::parse(s).unwrap().to_string();
+        assert_eq!(printf("%-10s|%08.3f|%x|%%|%1$s|{%d}"), "{:<10}|{:08.3}|{:x}|%|{0}|{{{}}}");
+        assert_eq!(printf("%5s %+d %#x %-05d %.2s %f %p"), "{:>5} {:+} {:#x} {:<5} {:.2} {:.6} {:p}");
+        assert_eq!(printf("%*d %s %2$.*3$f"), "{1:0$} {2} {1:.2$}");
+        assert_eq!(printf("%05s|%-05c|%05d"), "{:>5}|{:<5}|{:05}");
+        let args: &[&dyn FormatArg] = &[&"ab", &'c', &-7];
+        assert_eq!(printf::parse("%05s|%03c|%05d").unwrap().render_to_string(&args).unwrap(), "   ab|  c|-0007");
+
+        let unsupported = |s| match printf::parse(s) {
+            Err(ParseError::Unsupported { feature, span }) => (feature, span),
+            r => panic!("unexpected result: {:?}", r),
+        };
+        assert_eq!(unsupported("n = %ld"), ("length modifier 'l'".to_string(), Span::new(5, 6)));
+        assert_eq!(unsupported("%hhu"), ("length modifier 'hh'".to_string(), Span::new(1, 3)));
+        assert_eq!(unsupported("%10.3e"), ("conversion 'e'".to_string(), Span::new(5, 6)));
+        assert_eq!(unsupported("%#o"), ("flag '#' with conversion 'o'".to_string(), Span::new(2, 3)));
+        assert_eq!(unsupported("%.3d"), ("precision with conversion 'd'".to_string(), Span::new(1, 3)));
+        assert_eq!(unsupported("% d"), ("flag ' '".to_string(), Span::new(1, 2)));
+
+        match printf::parse("%0$s") {
+            Err(ParseError::InvalidInteger { span, .. }) => assert_eq!(span, Span::new(1, 2)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match printf::parse("%k") {
+            Err(ParseError::UnknownFormatType { value, .. }) => assert_eq!(value, "k"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match printf::parse("100%") {
+            Err(ParseError::UnexpectedEnd { pos }) => assert_eq!(pos, 4),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        match FormatString::parse("abc } def") {
+            Err(ParseError::UnescapedBrace { c: '}', span }) => assert_eq!(span, Span::new(4, 5)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("abc {name") {
+            Err(ParseError::UnexpectedEnd { pos }) => assert_eq!(pos, 9),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("{a b}") {
+            Err(ParseError::UnexpectedChar { c: 'b', span }) => assert_eq!(span, Span::new(3, 4)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("x {99999999999999999999999}") {
+            Err(ParseError::InvalidInteger { ref value, span, .. }) => {
+                assert_eq!(value, "99999999999999999999999");
+                assert_eq!(span, Span::new(3, 26));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("{:zz}") {
+            Err(ParseError::UnknownFormatType { ref value, span }) => {
+                assert_eq!(value, "zz");
+                assert_eq!(span, Span::new(2, 4));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("{_}") {
+            Err(ParseError::InvalidName { ref value, span }) => {
+                assert_eq!(value, "_");
+                assert_eq!(span, Span::new(1, 2));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn render() {
+        macro_rules! check {
+            ($fmt:literal, $($arg:expr),*) => {{
+                let args: &[&dyn FormatArg] = &[$(&$arg),*];
+                let out = FormatString::parse($fmt).unwrap().render_to_string(&args).unwrap();
+                assert_eq!(out, format!($fmt, $($arg),*), "{}", $fmt);
+            }};
+        }
+
+        check!("{{x}} = {} and {:?}!", 42, "quoted\n");
+        check!("[{0:*^+#12.3}] [{0:<9e}] [{0:+E}] [{0:08.2}]", -1.23456f64);
+        check!("[{0:#010b}] [{0:#o}] [{0:x}] [{0:#X}] [{0:x?}] [{0:#X?}] [{0:>+5}]", 255u8);
+        check!("[{2:>0$.1$}] [{3:.*}] [{:<5}] [{3:^+9.1e}]", 5usize, 2usize, "hello", 1.55f64);
+        check!("[{:-^7}] [{:7}] [{:>3}] [{:?}]", 'x', true, "abcdef", -0.0f32);
+        check!("[{:#?}] [{:e}] [{:5}|{:<5}|{:^5}]", 7i64, 1234567u32, "ab", 1i8, false);
+        check!("[{:.*}] [{}] [{:>6$.*}] [{}]", 2usize, 1.555f64, 'z', 3usize, 2.5f64, "end", 8usize);
+
+        let args: &[&dyn FormatArg] = &[&"text", &-1];
+        match FormatString::parse("{} {name}").unwrap().render_to_string(&args) {
+            Err(RenderError::MissingArgument { arg, span }) => {
+                assert_eq!(arg, Argument::from("name"));
+                assert_eq!(span, Span::new(4, 8));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("{:x}").unwrap().render_to_string(&args) {
+            Err(RenderError::UnsupportedFormat { arg, format_type, .. }) => {
+                assert_eq!(arg, Argument::Index(0));
+                assert_eq!(format_type, FormatType::LowerHex);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse("{0:1$} {0:0$}").unwrap().render_to_string(&args) {
+            Err(RenderError::InvalidCount { count, span }) => {
+                assert_eq!(count, Count::Argument(Spanned::new(Argument::Index(1), Span::new(3, 4))));
+                assert_eq!(span, Span::new(3, 4));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let f = FormatString::builder().arg(0).spec(FormatSpec::new().with_width(70000)).build();
+        for r in [f.render_to_string(&args), f.compile().render_to_string(&args)].iter() {
+            match *r {
+                Err(ref err @ RenderError::InvalidCount { count: Count::Value(70000), .. }) => assert_eq!(err.to_string(), "count 70000 at position 0 is too large"),
+                ref r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        let (f, _) = FormatString::parse_recovering("{} {:q}");
+        match f.render_to_string(&args) {
+            Err(RenderError::InvalidFormat { span }) => assert_eq!(span.start, 3),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn compile() {
+        let args: &[&dyn FormatArg] = &[&5usize, &2usize, &"hello", &1.55f64];
+        let fmts = [
+            "[{2:>0$.1$}] [{3:.*}] [{:<5}] {{x}} [{3:^+9.1e}]",
+            "{} {:?} {2:#x?} {3:*<+10.1$}",
+            "{3:08.3} {name}",
+            "{0:1$} {0:2$}",
+            "{2:x}",
+            "",
+        ];
+        for fmt in fmts.iter() {
+            let f = FormatString::parse(fmt).unwrap();
+            let compiled = f.compile();
+            assert_eq!(compiled.render_to_string(&args).map_err(|e| e.to_string()), f.render_to_string(&args).map_err(|e| e.to_string()), "{}", fmt);
+        }
+        let (f, _) = FormatString::parse_recovering("{} {:q}");
+        match f.compile().render_to_string(&args) {
+            Err(RenderError::InvalidFormat { span }) => assert_eq!(span.start, 3),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn render_display_width() {
+        let args: &[&dyn FormatArg] = &[&"日本", &'字', &12, &crate::Value::from("ａｂ"), &4usize];
+        let f = FormatString::parse("[{0:6}] [{0:>4$}] [{1:-^4$}] [{2:4}] [{3:.3}] [{0:?}]").unwrap();
+        let options = RenderOptions::new().with_display_width(true);
+        let expected = "[日本  ] [日本] [-字-] [  12] [ａ] [\"日本\"]";
+        let mut out = String::new();
+        f.render_with(&args, &mut out, &options).unwrap();
+        assert_eq!(out, expected);
+        out.clear();
+        f.compile().render_with(&args, &mut out, &options).unwrap();
+        assert_eq!(out, expected);
+        assert_eq!(f.render_to_string(&args).unwrap(), format!("[{0:6}] [{0:>4$}] [{1:-^4$}] [{2:4}] [{3:.3}] [{0:?}]", "日本", '字', 12, "ａｂ", 4));
+    }
+
+    #[test]
+    fn argument_providers() {
+        let f = FormatString::parse("{} {:>5} {0}").unwrap();
+        let (a, b) = (1.5, String::from("b"));
+        let display: &[&dyn std::fmt::Display] = &[&a, &b];
+        assert_eq!(f.render_to_string(&display).unwrap(), "1.5     b 1.5");
+        assert_eq!(f.render_to_string(&[10, 20]).unwrap(), "10    20 10");
+        assert_eq!(f.render_to_string(&vec!["x", "y"]).unwrap(), "x     y x");
+        match FormatString::parse("{:?}").unwrap().render_to_string(&display) {
+            Err(RenderError::UnsupportedFormat { format_type: FormatType::Debug, .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let f = FormatString::parse("{user}: {count:>0$}").unwrap();
+        let mut hash_map = std::collections::HashMap::new();
+        hash_map.insert("user", 4usize);
+        assert_eq!(f.render_to_string(&hash_map).map_err(|e| e.to_string()), Err("missing argument 'count' at position 9".into()));
+        hash_map.insert("count", 12);
+        match f.render_to_string(&hash_map) {
+            Err(RenderError::MissingArgument { arg, span }) => {
+                assert_eq!(arg, Argument::Index(0));
+                assert_eq!(span, Span::new(16, 17));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut btree_map = std::collections::BTreeMap::new();
+        btree_map.insert(String::from("user"), "john");
+        btree_map.insert(String::from("count"), "3");
+        assert_eq!(FormatString::parse("{user}/{count}").unwrap().render_to_string(&btree_map).unwrap(), "john/3");
+
+        let (x, user, width) = (7u8, "john", 4);
+        let args = ArgList::new().positional(&x).positional(&width).named("user", &user).named("user", &"jane");
+        assert_eq!(FormatString::parse("{user}: {:#>1$x}|{:?}").unwrap().render_to_string(&args).unwrap(), "jane: ###7|4");
+        match FormatString::parse("{2} {user}").unwrap().render_to_string(&args) {
+            Err(RenderError::MissingArgument { arg, .. }) => assert_eq!(arg, Argument::Index(2)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let value: crate::Value = vec![("user", "john")].into_iter().collect();
+        assert_eq!(FormatString::parse("[{user:^8}]").unwrap().render_to_string(&value).unwrap(), "[  john  ]");
+    }
+
+    #[test]
+    fn field_paths() {
+        let options = ParseOptions::new().with_field_paths(true);
+        let f = FormatString::parse_with("{order.lines[2].sku:>8} {0[1]} {r#type.a}", &options).unwrap();
+        assert_eq!(f.to_string(), "{order.lines[2].sku:>8} {0[1]} {type.a}");
+        let args: Vec<_> = f.argument_uses().into_iter().map(|u| (u.arg().node().clone(), u.arg().span())).collect();
+        assert_eq!(args, vec![
+            (Argument::Path(vec![PathSegment::Field("order".into()), PathSegment::Field("lines".into()), PathSegment::Index(2), PathSegment::Field("sku".into())].into()), Span::new(1, 19)),
+            (Argument::Path(vec![PathSegment::Index(0), PathSegment::Index(1)].into()), Span::new(25, 29)),
+            (Argument::Path(vec![PathSegment::Field("type".into()), PathSegment::Field("a".into())].into()), Span::new(32, 40)),
+        ]);
+        assert_eq!(FormatString::parse_with("{a} {0:w$}", &options).unwrap(), FormatString::parse("{a} {0:w$}").unwrap());
+
+        match FormatString::parse("{a.b}") {
+            Err(ParseError::UnexpectedChar { c: '.', span }) => assert_eq!(span, Span::new(2, 3)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse_with("{a.0}", &options) {
+            Err(ParseError::UnexpectedChar { c: '0', span }) => assert_eq!(span, Span::new(3, 4)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match FormatString::parse_with("{a[x]}", &options) {
+            Err(ParseError::UnexpectedChar { c: 'x', span }) => assert_eq!(span, Span::new(3, 4)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        struct Line {
+            sku: String,
+            qty: u32,
+        }
+
+        impl FormatArg for Line {
+            fn fmt(&self, _format_type: &FormatType, _f: &mut std::fmt::Formatter) -> Option<std::fmt::Result> {
+                None
+            }
+
+            fn field(&self, name: &str) -> Option<&dyn FormatArg> {
+                match name {
+                    "sku" => Some(&self.sku),
+                    "qty" => Some(&self.qty),
+                    _ => None,
+                }
+            }
+        }
+
+        let lines = vec![Line { sku: "A-1".into(), qty: 2 }, Line { sku: "B-22".into(), qty: 10 }];
+        let mut order = std::collections::HashMap::new();
+        order.insert("lines", lines);
+        let args = ArgList::new().named("order", &order);
+        let f = FormatString::parse_with("{order.lines[1].sku:>6}|{order.lines[0].qty:03}", &options).unwrap();
+        assert_eq!(f.render_to_string(&args).unwrap(), "  B-22|002");
+        let f = FormatString::parse_with("{order.lines[2].sku}", &options).unwrap();
+        assert_eq!(f.render_to_string(&args).unwrap_err().to_string(), "missing argument 'order.lines[2].sku' at position 1");
+
+        let value: crate::Value = vec![("user", vec![("names", vec!["ann", "bob"])].into_iter().collect::<crate::Value>())].into_iter().collect();
+        let f = FormatString::parse_with("{user.names[1]:?} {0.user.names[0]}", &options).unwrap();
+        let values = [&value];
+        assert_eq!(f.render_to_string(&value).map_err(|e| e.to_string()), Err("missing argument '0.user.names[0]' at position 19".into()));
+        assert_eq!(FormatString::parse_with("{0.user.names[0]}", &options).unwrap().render_to_string(&values).unwrap(), "ann");
+    }
 }
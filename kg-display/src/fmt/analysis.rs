@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FormatTrait {
+    Display,
+    Debug,
+    Octal,
+    LowerHex,
+    UpperHex,
+    Pointer,
+    Binary,
+    LowerExp,
+    UpperExp,
+    Custom(String),
+}
+
+impl std::fmt::Display for FormatTrait {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            FormatTrait::Display => write!(f, "Display"),
+            FormatTrait::Debug => write!(f, "Debug"),
+            FormatTrait::Octal => write!(f, "Octal"),
+            FormatTrait::LowerHex => write!(f, "LowerHex"),
+            FormatTrait::UpperHex => write!(f, "UpperHex"),
+            FormatTrait::Pointer => write!(f, "Pointer"),
+            FormatTrait::Binary => write!(f, "Binary"),
+            FormatTrait::LowerExp => write!(f, "LowerExp"),
+            FormatTrait::UpperExp => write!(f, "UpperExp"),
+            FormatTrait::Custom(ref name) => write!(f, "Custom({})", name),
+        }
+    }
+}
+
+impl FormatType {
+    pub fn format_trait(&self) -> FormatTrait {
+        match *self {
+            FormatType::Display => FormatTrait::Display,
+            FormatType::Debug | FormatType::DebugLowerHex | FormatType::DebugUpperHex => FormatTrait::Debug,
+            FormatType::Octal => FormatTrait::Octal,
+            FormatType::LowerHex => FormatTrait::LowerHex,
+            FormatType::UpperHex => FormatTrait::UpperHex,
+            FormatType::Pointer => FormatTrait::Pointer,
+            FormatType::Binary => FormatTrait::Binary,
+            FormatType::LowerExp => FormatTrait::LowerExp,
+            FormatType::UpperExp => FormatTrait::UpperExp,
+            FormatType::Custom(ref name) => FormatTrait::Custom(name.clone()),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ArgumentRole {
+    Value(FormatTrait),
+    Width,
+    Precision,
+}
+
+impl ArgumentRole {
+    pub fn format_trait(&self) -> Option<FormatTrait> {
+        match *self {
+            ArgumentRole::Value(ref t) => Some(t.clone()),
+            ArgumentRole::Width | ArgumentRole::Precision => None,
+        }
+    }
+
+    pub fn is_count(&self) -> bool {
+        self.format_trait().is_none()
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArgumentUse<'a> {
+    arg: Spanned<Argument<'a>>,
+    role: ArgumentRole,
+}
+
+impl<'a> ArgumentUse<'a> {
+    pub fn arg(&self) -> &Spanned<Argument<'a>> {
+        &self.arg
+    }
+
+    pub fn role(&self) -> ArgumentRole {
+        self.role.clone()
+    }
+}
+
+
+impl<'a> FormatString<'a> {
+    pub fn argument_uses(&self) -> Vec<ArgumentUse<'a>> {
+        struct Collector<'a> {
+            uses: Vec<ArgumentUse<'a>>,
+        }
+
+        impl<'a> Collector<'a> {
+            fn push_count(&mut self, count: &Spanned<Count<'a>>, role: ArgumentRole) {
+                if let Count::Argument(ref arg) = **count {
+                    self.uses.push(ArgumentUse { arg: arg.clone(), role });
+                }
+            }
+        }
+
+        impl<'a> Visit<'a> for Collector<'a> {
+            fn visit_format(&mut self, node: &Format<'a>) {
+                let format_trait = node.spec().map_or(FormatTrait::Display, |s| s.format_type().format_trait());
+                self.uses.push(ArgumentUse {
+                    arg: node.arg().clone(),
+                    role: ArgumentRole::Value(format_trait),
+                });
+                visit::visit_format(self, node);
+            }
+
+            fn visit_width(&mut self, node: &Spanned<Count<'a>>) {
+                self.push_count(node, ArgumentRole::Width);
+            }
+
+            fn visit_precision(&mut self, node: &Spanned<Precision<'a>>) {
+                if let Precision::Count(ref count) = **node {
+                    self.push_count(count, ArgumentRole::Precision);
+                }
+            }
+        }
+
+        let mut f = self.clone();
+        f.normalize();
+        let mut c = Collector { uses: Vec::new() };
+        c.visit_format_string(&f);
+        c.uses
+    }
+
+    /// Returns `true` if both format strings use the same arguments in the same roles and
+    /// with the same formatting traits, regardless of order, repetition and formatting options.
+    pub fn is_compatible_with(&self, other: &FormatString<'a>) -> bool {
+        let uses: HashSet<ArgumentUse> = self.argument_uses().into_iter().collect();
+        let other_uses: HashSet<ArgumentUse> = other.argument_uses().into_iter().collect();
+        uses == other_uses
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt::{Formatter, FormattingOptions, Write};
+
+use super::*;
+use super::render::{argument, base_options, count, format_argument};
+
+/// Format string prepared for repeated rendering.
+///
+/// Implicit arguments are numbered, adjacent literal text and escapes are joined, and
+/// formatting options are computed once, leaving only argument lookups and width and precision
+/// arguments to be resolved by `render`.
+#[derive(Debug, Clone)]
+pub struct CompiledFormat {
+    instructions: Vec<Instruction>,
+}
+
+#[derive(Debug, Clone)]
+enum Instruction {
+    Literal(String),
+    Format(FormatInstruction),
+    Invalid(Span),
+}
+
+#[derive(Debug, Clone)]
+struct FormatInstruction {
+    arg: Spanned<Argument<'static>>,
+    format_type: FormatType,
+    options: FormattingOptions,
+    width: Option<Spanned<Count<'static>>>,
+    precision: Option<Spanned<Count<'static>>>,
+}
+
+impl CompiledFormat {
+    /// Formats `args` the same way as `FormatString::render` on the compiled format string.
+    pub fn render(&self, args: &dyn Args, out: &mut dyn Write) -> Result<(), RenderError> {
+        self.render_with(args, out, &RenderOptions::new())
+    }
+
+    pub fn render_with(&self, args: &dyn Args, out: &mut dyn Write, render_options: &RenderOptions) -> Result<(), RenderError> {
+        for instruction in self.instructions.iter() {
+            match *instruction {
+                Instruction::Literal(ref s) => out.write_str(s)?,
+                Instruction::Format(ref f) => {
+                    let value = argument(args, &f.arg)?;
+                    let mut options = f.options;
+                    if let Some(ref width) = f.width {
+                        options.width(Some(count(width, args)?));
+                    }
+                    if let Some(ref precision) = f.precision {
+                        options.precision(Some(count(precision, args)?));
+                    }
+                    format_argument(value, &f.arg, &f.format_type, &mut Formatter::new(out, options), render_options)?;
+                }
+                Instruction::Invalid(span) => return Err(RenderError::InvalidFormat { span }),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render_to_string(&self, args: &dyn Args) -> Result<String, RenderError> {
+        let mut out = String::new();
+        self.render(args, &mut out)?;
+        Ok(out)
+    }
+}
+
+
+/// Sets a literal count in `options`, returning counts left to resolve (or reject) on render.
+fn compile_count(count: &Spanned<Count>, options: &mut FormattingOptions, set: fn(&mut FormattingOptions, Option<u16>) -> &mut FormattingOptions) -> Option<Spanned<Count<'static>>> {
+    match **count {
+        Count::Value(value) if value <= u16::MAX as usize => {
+            set(options, Some(value as u16));
+            None
+        }
+        _ => Some(count.clone().map(Count::into_owned)),
+    }
+}
+
+impl<'a> FormatString<'a> {
+    pub fn compile(&self) -> CompiledFormat {
+        let mut f = self.clone();
+        f.normalize();
+        let instructions = f.0.into_iter().map(|item| {
+            let span = item.span();
+            match item.into_node() {
+                FormatStringItem::Text(s) => Instruction::Literal(s.into_owned()),
+                FormatStringItem::Escape(c) => Instruction::Literal(c.to_string()),
+                FormatStringItem::Format(fmt) => {
+                    let spec = fmt.spec.map_or_else(FormatSpec::new, Spanned::into_node);
+                    let mut options = base_options(&spec);
+                    let width = spec.width.as_ref().and_then(|w| compile_count(w, &mut options, FormattingOptions::width));
+                    let precision = match spec.precision.as_ref().map(|p| p.node()) {
+                        Some(Precision::Count(c)) => compile_count(c, &mut options, FormattingOptions::precision),
+                        Some(Precision::Star) => unreachable!("format string is normalized"),
+                        None => None,
+                    };
+                    Instruction::Format(FormatInstruction {
+                        arg: fmt.arg.map(Argument::into_owned),
+                        format_type: spec.format_type,
+                        options,
+                        width,
+                        precision,
+                    })
+                }
+                FormatStringItem::Error(_) => Instruction::Invalid(span),
+            }
+        }).collect();
+        CompiledFormat { instructions }
+    }
+}
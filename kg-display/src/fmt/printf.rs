@@ -0,0 +1,202 @@
+use std::num::NonZeroU16;
+
+use super::*;
+
+/// Parses a C `printf` template into a `FormatString` that formats the same way.
+///
+/// Supported are `%%`, positional `%n$` arguments, the `-`, `+`, `#` (for `x` only) and `0`
+/// flags, widths and precisions given literally or through `*` and `*n$`, and the `d`, `i`,
+/// `u`, `s`, `c`, `o`, `x`, `X`, `f` and `p` conversions. `f` without a precision gets the
+/// default precision of 6, and right alignment is made explicit for `s` and `c`, which Rust
+/// pads on the right by default. Like in glibc, the `0` flag pads `s` and `c` with spaces. Arguments taken from the argument list in order stay
+/// implicit as long as Rust numbers them the same way and become explicit indices after that.
+///
+/// Length modifiers, the `' '` and `'` flags, precisions on integer conversions and the
+/// conversions with no Rust equivalent are rejected with `ParseError::Unsupported` covering
+/// the offending part.
+pub fn parse(fmt_str: &str) -> Result<FormatString<'_>, ParseError> {
+    let mut p = Parser {
+        chars: fmt_str.char_indices(),
+        auto: 0,
+        implicit: 0,
+    };
+    let mut items = Vec::new();
+    let mut start = 0;
+    while let Some((i, c)) = p.chars.next() {
+        if c != '%' {
+            continue;
+        }
+        text(fmt_str, start, i, &mut items);
+        if consume(&mut p.chars, '%') {
+            text(fmt_str, i + 1, i + 2, &mut items);
+        } else {
+            let f = p.format()?;
+            items.push(Spanned::new(FormatStringItem::Format(f), Span::new(i, p.chars.offset())));
+        }
+        start = p.chars.offset();
+    }
+    text(fmt_str, start, fmt_str.len(), &mut items);
+    Ok(FormatString(items))
+}
+
+fn text<'a>(fmt_str: &'a str, mut start: usize, end: usize, items: &mut Vec<Spanned<FormatStringItem<'a>>>) {
+    while let Some(pos) = fmt_str[start..end].find(['{', '}']) {
+        if pos > 0 {
+            items.push(Spanned::new(FormatStringItem::Text(Cow::Borrowed(&fmt_str[start..start + pos])), Span::new(start, start + pos)));
+        }
+        let c = fmt_str[start + pos..].chars().next().unwrap();
+        items.push(Spanned::new(FormatStringItem::Escape(c), Span::of_char(start + pos, c)));
+        start += pos + 1;
+    }
+    if start < end {
+        items.push(Spanned::new(FormatStringItem::Text(Cow::Borrowed(&fmt_str[start..end])), Span::new(start, end)));
+    }
+}
+
+
+struct Parser<'a> {
+    chars: CharIndices<'a>,
+    auto: usize,
+    implicit: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn format(&mut self) -> Result<Format<'a>, ParseError> {
+        let start = self.chars.offset();
+        let arg = self.position()?;
+
+        let mut spec = FormatSpec::new();
+        let mut left = false;
+        let mut space = None;
+        loop {
+            match peek(&self.chars) {
+                Some('-') => left = true,
+                Some('+') => spec.sign = Some(Sign::Plus),
+                Some('#') => spec.alter = true,
+                Some('0') => spec.zero = true,
+                Some(' ') => space = Some(self.chars.offset()),
+                Some('\'') => return Err(ParseError::unsupported("flag '''", Span::of_char(self.chars.offset(), '\''))),
+                _ => break,
+            }
+            self.chars.next();
+        }
+        if let (Some(pos), None) = (space, spec.sign) {
+            return Err(ParseError::unsupported("flag ' '", Span::of_char(pos, ' ')));
+        }
+
+        spec.width = self.count()?;
+        let precision_start = self.chars.offset();
+        if consume(&mut self.chars, '.') {
+            let count = match self.count()? {
+                Some(count) => count,
+                None => Spanned::new(Count::Value(0), Span::new(self.chars.offset(), self.chars.offset())),
+            };
+            let span = Span::new(precision_start, count.span().end);
+            spec.precision = Some(Spanned::new(Precision::Count(count), span));
+        }
+
+        let length_start = self.chars.offset();
+        let length = take_while(&mut self.chars, |c| "hlLqjzt".contains(c));
+        if !length.is_empty() {
+            return Err(ParseError::unsupported(format!("length modifier '{}'", length), Span::new(length_start, self.chars.offset())));
+        }
+
+        let (i, c) = match self.chars.next() {
+            Some(next) => next,
+            None => return Err(ParseError::unexpected_end(&self.chars)),
+        };
+        let span = Span::of_char(i, c);
+        if spec.alter && c != 'x' {
+            return Err(ParseError::unsupported(format!("flag '#' with conversion '{}'", c), span));
+        }
+        if spec.precision.is_some() && "diuoxXc".contains(c) {
+            return Err(ParseError::unsupported(format!("precision with conversion '{}'", c), spec.precision_span().unwrap()));
+        }
+        spec.format_type = match c {
+            'd' | 'i' | 'u' | 's' | 'c' => FormatType::Display,
+            'f' => {
+                if spec.precision.is_none() {
+                    spec.precision = Some(Spanned::new(Precision::from(6), span));
+                }
+                FormatType::Display
+            }
+            'o' => FormatType::Octal,
+            'x' => FormatType::LowerHex,
+            'X' => FormatType::UpperHex,
+            'p' => FormatType::Pointer,
+            'e' | 'E' | 'F' | 'g' | 'G' | 'a' | 'A' | 'n' | 'C' | 'S' => return Err(ParseError::unsupported(format!("conversion '{}'", c), span)),
+            _ => return Err(ParseError::UnknownFormatType { value: c.to_string(), span }),
+        };
+
+        if left || c == 's' || c == 'c' {
+            spec.zero = false;
+        }
+        if left && spec.width.is_some() {
+            spec.fill_align = Some(FillAlign::from(Align::Left));
+        } else if spec.width.is_some() && !spec.zero && (c == 's' || c == 'c') {
+            spec.fill_align = Some(FillAlign::from(Align::Right));
+        }
+        if spec.width.is_none() {
+            spec.zero = false;
+        }
+
+        let arg = match arg {
+            Some(arg) => arg,
+            None if self.auto == self.implicit => {
+                self.auto += 1;
+                self.implicit += 1;
+                Spanned::new(Argument::Next, Span::new(start, start))
+            }
+            None => {
+                self.auto += 1;
+                Spanned::new(Argument::Index(self.auto - 1), Span::new(start, start))
+            }
+        };
+        Ok(Format {
+            arg,
+            spec: if spec.is_empty() { None } else { Some(Spanned::new(spec, Span::new(start, self.chars.offset()))) },
+        })
+    }
+
+    fn position(&mut self) -> Result<Option<Spanned<Argument<'a>>>, ParseError> {
+        let prev = self.chars.clone();
+        let start = self.chars.offset();
+        let value = take_while(&mut self.chars, |c| c.is_ascii_digit());
+        if value.is_empty() || !consume(&mut self.chars, '$') {
+            self.chars = prev;
+            return Ok(None);
+        }
+        let span = Span::new(start, self.chars.offset() - 1);
+        let index = value.parse::<NonZeroU16>().map_err(|err| ParseError::InvalidInteger {
+            value: value.to_string(),
+            span,
+            err,
+        })?;
+        Ok(Some(Spanned::new(Argument::Index(index.get() as usize - 1), span)))
+    }
+
+    fn count(&mut self) -> Result<Option<Spanned<Count<'a>>>, ParseError> {
+        let start = self.chars.offset();
+        if consume(&mut self.chars, '*') {
+            let arg = match self.position()? {
+                Some(arg) => arg,
+                None => {
+                    self.auto += 1;
+                    Spanned::new(Argument::Index(self.auto - 1), Span::new(start, start + 1))
+                }
+            };
+            return Ok(Some(Spanned::new(Count::Argument(arg), Span::new(start, self.chars.offset()))));
+        }
+        let value = take_while(&mut self.chars, |c| c.is_ascii_digit());
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let span = Span::new(start, self.chars.offset());
+        let value = value.parse::<u16>().map_err(|err| ParseError::InvalidInteger {
+            value: value.to_string(),
+            span,
+            err,
+        })?;
+        Ok(Some(Spanned::new(Count::Value(value as usize), span)))
+    }
+}
@@ -0,0 +1,279 @@
+use super::*;
+
+/// Parses a Python `str.format` template into a `FormatString` that formats the same way.
+///
+/// Python options are mapped onto their Rust counterparts: `!r` becomes `?`, `!s` is dropped,
+/// `d` and `s` become `Display`, `f` becomes `Display` with a default precision of 6, the `0`
+/// flag or `0=` alignment become the `0` flag, and `{}` fields nested in the width or precision
+/// become `$` arguments. Automatically numbered fields stay implicit as long as Rust numbers
+/// them the same way and become explicit indices after that. Like in Python, mixing them with
+/// manually numbered fields is an error (`ParseError::MixedNumbering`).
+///
+/// Features with no Rust equivalent (attribute and index access, `!a`, `=` alignment with
+/// another fill, the `' '` sign, `z`, grouping, and the `c`, `e`, `E`, `F`, `g`, `G`, `n` and
+/// `%` types) are rejected with `ParseError::Unsupported` covering the offending part.
+pub fn parse(fmt_str: &str) -> Result<FormatString<'_>, ParseError> {
+    let mut p = Parser {
+        chars: fmt_str.char_indices(),
+        auto: 0,
+        implicit: 0,
+        manual: false,
+    };
+    let mut items = Vec::new();
+    let mut start = 0;
+    while let Some((i, c)) = p.chars.next() {
+        if c != '{' && c != '}' {
+            continue;
+        }
+        if start < i {
+            items.push(Spanned::new(FormatStringItem::Text(Cow::Borrowed(&fmt_str[start..i])), Span::new(start, i)));
+        }
+        let item = if consume(&mut p.chars, c) {
+            FormatStringItem::Escape(c)
+        } else if c == '{' {
+            FormatStringItem::Format(p.format()?)
+        } else {
+            return Err(ParseError::UnescapedBrace { c, span: Span::of_char(i, c) });
+        };
+        start = p.chars.offset();
+        items.push(Spanned::new(item, Span::new(i, start)));
+    }
+    if start < fmt_str.len() {
+        items.push(Spanned::new(FormatStringItem::Text(Cow::Borrowed(&fmt_str[start..])), Span::new(start, fmt_str.len())));
+    }
+    Ok(FormatString(items))
+}
+
+
+struct Parser<'a> {
+    chars: CharIndices<'a>,
+    auto: usize,
+    implicit: usize,
+    manual: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn format(&mut self) -> Result<Format<'a>, ParseError> {
+        let name = self.field_name()?;
+        let span = name.span();
+        self.check_numbering(&name)?;
+        let arg = match name.into_node() {
+            Some(arg) => arg,
+            None if self.auto == self.implicit => {
+                self.auto += 1;
+                self.implicit += 1;
+                Argument::Next
+            }
+            None => {
+                self.auto += 1;
+                Argument::Index(self.auto - 1)
+            }
+        };
+        let mut f = Format {
+            arg: Spanned::new(arg, span),
+            spec: None,
+        };
+
+        let mut repr = None;
+        let conv_start = self.chars.offset();
+        if consume(&mut self.chars, '!') {
+            match self.chars.next() {
+                Some((_, 's')) => {}
+                Some((i, 'r')) => repr = Some(Span::new(conv_start, i + 1)),
+                Some((i, 'a')) => return Err(ParseError::unsupported("conversion '!a'", Span::new(conv_start, i + 1))),
+                Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+                None => return Err(ParseError::unexpected_end(&self.chars)),
+            }
+        }
+        if consume(&mut self.chars, ':') {
+            let start = self.chars.offset();
+            let spec = self.spec()?;
+            if !spec.is_empty() {
+                f.spec = Some(Spanned::new(spec, Span::new(start, self.chars.offset())));
+            }
+        }
+        if let Some(span) = repr {
+            let spec = f.spec.get_or_insert_with(|| Spanned::new(FormatSpec::new(), span));
+            match spec.format_type {
+                FormatType::Display => spec.format_type = FormatType::Debug,
+                _ => return Err(ParseError::unsupported("format type with conversion '!r'", spec.span())),
+            }
+        }
+
+        match self.chars.next() {
+            Some((_, '}')) => Ok(f),
+            Some((i, c)) => Err(ParseError::unexpected_char(i, c)),
+            None => Err(ParseError::unexpected_end(&self.chars)),
+        }
+    }
+
+    fn field_name(&mut self) -> Result<Spanned<Option<Argument<'a>>>, ParseError> {
+        let start = self.chars.offset();
+        let name = take_while(&mut self.chars, |c| !".[!:{}".contains(c));
+        let span = Span::new(start, self.chars.offset());
+        match peek(&self.chars) {
+            Some('.') => return Err(ParseError::unsupported("attribute access", Span::of_char(span.end, '.'))),
+            Some('[') => return Err(ParseError::unsupported("index access", Span::of_char(span.end, '['))),
+            _ => {}
+        }
+        let arg = if name.is_empty() {
+            None
+        } else if name.bytes().all(|b| b.is_ascii_digit()) {
+            let index = name.parse::<u16>().map_err(|err| ParseError::InvalidInteger {
+                value: name.to_string(),
+                span,
+                err,
+            })?;
+            Some(Argument::Index(index as usize))
+        } else if is_valid_name(name) {
+            Some(Argument::Name(Cow::Borrowed(name)))
+        } else {
+            return Err(ParseError::unsupported(format!("argument name '{}'", name), span));
+        };
+        Ok(Spanned::new(arg, span))
+    }
+
+    fn check_numbering(&mut self, name: &Spanned<Option<Argument<'a>>>) -> Result<(), ParseError> {
+        let manual = match **name {
+            Some(Argument::Index(_)) => true,
+            Some(_) => return Ok(()),
+            None => false,
+        };
+        if (manual && self.auto > 0) || (!manual && self.manual) {
+            return Err(ParseError::MixedNumbering { span: name.span() });
+        }
+        self.manual = manual;
+        Ok(())
+    }
+
+    fn nested_field(&mut self) -> Result<Spanned<Count<'a>>, ParseError> {
+        let start = self.chars.offset();
+        self.chars.next();
+        let name = self.field_name()?;
+        let span = name.span();
+        self.check_numbering(&name)?;
+        let arg = match name.into_node() {
+            Some(arg) => arg,
+            None => {
+                self.auto += 1;
+                Argument::Index(self.auto - 1)
+            }
+        };
+        match self.chars.next() {
+            Some((_, '}')) => {}
+            Some((i, '!')) => return Err(ParseError::unsupported("conversion in nested field", Span::of_char(i, '!'))),
+            Some((i, ':')) => return Err(ParseError::unsupported("format spec in nested field", Span::of_char(i, ':'))),
+            Some((i, c)) => return Err(ParseError::unexpected_char(i, c)),
+            None => return Err(ParseError::unexpected_end(&self.chars)),
+        }
+        Ok(Spanned::new(Count::Argument(Spanned::new(arg, span)), Span::new(start, self.chars.offset())))
+    }
+
+    fn count(&mut self) -> Result<Option<Spanned<Count<'a>>>, ParseError> {
+        let start = self.chars.offset();
+        match peek(&self.chars) {
+            Some('{') => self.nested_field().map(Some),
+            Some(c) if c.is_ascii_digit() => {
+                let value = take_while(&mut self.chars, |c| c.is_ascii_digit());
+                let span = Span::new(start, self.chars.offset());
+                let value = value.parse::<u16>().map_err(|err| ParseError::InvalidInteger {
+                    value: value.to_string(),
+                    span,
+                    err,
+                })?;
+                Ok(Some(Spanned::new(Count::Value(value as usize), span)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn spec(&mut self) -> Result<FormatSpec<'a>, ParseError> {
+        let mut spec = FormatSpec::new();
+
+        let start = self.chars.offset();
+        let mut next = self.chars.as_str().chars();
+        let (fill, align) = match (next.next(), next.next()) {
+            (Some(f), Some(a)) if f != '{' && f != '}' && "<>^=".contains(a) => (Some(f), Some(a)),
+            (Some(a), _) if "<>^=".contains(a) => (None, Some(a)),
+            _ => (None, None),
+        };
+        self.chars.advance_by(fill.iter().chain(align.iter()).count()).unwrap();
+        match align {
+            Some('=') if fill == Some('0') => spec.zero = true,
+            Some('=') => return Err(ParseError::unsupported("'=' alignment", Span::new(start, self.chars.offset()))),
+            Some(a) => spec.fill_align = Some(FillAlign::new(fill, Align::try_from(a).unwrap())),
+            None => {}
+        }
+
+        match peek(&self.chars) {
+            Some('+') => spec.sign = Some(Sign::Plus),
+            Some('-') => spec.sign = Some(Sign::Minus),
+            Some(' ') => return Err(ParseError::unsupported("sign ' '", Span::of_char(self.chars.offset(), ' '))),
+            _ => {}
+        }
+        if spec.sign.is_some() {
+            self.chars.next();
+        }
+        if peek(&self.chars) == Some('z') {
+            return Err(ParseError::unsupported("option 'z'", Span::of_char(self.chars.offset(), 'z')));
+        }
+        spec.alter = consume(&mut self.chars, '#');
+        if consume(&mut self.chars, '0') {
+            match spec.fill_align {
+                Some(ref mut fill_align) if fill_align.fill.is_none() => fill_align.fill = Some('0'),
+                Some(_) => {}
+                None => spec.zero = true,
+            }
+        }
+        spec.width = self.count()?;
+        if spec.width.is_none() {
+            spec.zero = false;
+        }
+        if let Some(c @ (',' | '_')) = peek(&self.chars) {
+            return Err(ParseError::unsupported(format!("grouping option '{}'", c), Span::of_char(self.chars.offset(), c)));
+        }
+
+        let precision_start = self.chars.offset();
+        if consume(&mut self.chars, '.') {
+            match self.count()? {
+                Some(count) => {
+                    let span = Span::new(precision_start, count.span().end);
+                    spec.precision = Some(Spanned::new(Precision::Count(count), span));
+                }
+                None => return match self.chars.next() {
+                    Some((i, c)) => Err(ParseError::unexpected_char(i, c)),
+                    None => Err(ParseError::unexpected_end(&self.chars)),
+                },
+            }
+        }
+
+        let type_start = self.chars.offset();
+        let c = match peek(&self.chars) {
+            Some(c) if c.is_ascii_alphabetic() || c == '%' => c,
+            Some('{') => return Err(ParseError::unsupported("nested field", Span::of_char(type_start, '{'))),
+            _ => return Ok(spec),
+        };
+        self.chars.next();
+        let span = Span::of_char(type_start, c);
+        spec.format_type = match c {
+            's' | 'd' => FormatType::Display,
+            'f' => {
+                if spec.precision.is_none() {
+                    spec.precision = Some(Spanned::new(Precision::from(6), span));
+                }
+                FormatType::Display
+            }
+            'b' => FormatType::Binary,
+            'o' => FormatType::Octal,
+            'x' => FormatType::LowerHex,
+            'X' if spec.alter => return Err(ParseError::unsupported("'#' with format type 'X'", span)),
+            'X' => FormatType::UpperHex,
+            'c' | 'e' | 'E' | 'F' | 'g' | 'G' | 'n' | '%' => return Err(ParseError::unsupported(format!("format type '{}'", c), span)),
+            _ => return Err(ParseError::UnknownFormatType { value: c.to_string(), span }),
+        };
+        if peek(&self.chars) == Some('{') {
+            return Err(ParseError::unsupported("nested field", Span::of_char(self.chars.offset(), '{')));
+        }
+        Ok(spec)
+    }
+}
@@ -0,0 +1,503 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Alignment, DebugAsHex, Formatter, FormattingOptions, Write};
+use std::hash::BuildHasher;
+
+use super::*;
+
+/// A value that can be formatted at runtime by a `FormatString`.
+pub trait FormatArg {
+    /// Formats the value with the trait selected by `format_type`, or returns `None` if the
+    /// value does not support it.
+    fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result>;
+
+    /// Returns the value as a width or precision, or `None` if it cannot be used as one.
+    fn to_count(&self) -> Option<usize> {
+        None
+    }
+
+    /// Returns the field `name` of the value, for resolving `Argument::Path`.
+    fn field(&self, _name: &str) -> Option<&dyn FormatArg> {
+        None
+    }
+
+    /// Returns the element at `index` of the value, for resolving `Argument::Path`.
+    fn index(&self, _index: usize) -> Option<&dyn FormatArg> {
+        None
+    }
+
+    /// Returns the value as text, if it displays as that text. Text is padded and truncated
+    /// in display columns when rendering with `RenderOptions::with_display_width`.
+    fn as_text(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+}
+
+impl<T: FormatArg + ?Sized> FormatArg for &T {
+    fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+        (**self).fmt(format_type, f)
+    }
+
+    fn to_count(&self) -> Option<usize> {
+        (**self).to_count()
+    }
+
+    fn field(&self, name: &str) -> Option<&dyn FormatArg> {
+        (**self).field(name)
+    }
+
+    fn index(&self, index: usize) -> Option<&dyn FormatArg> {
+        (**self).index(index)
+    }
+
+    fn as_text(&self) -> Option<Cow<'_, str>> {
+        (**self).as_text()
+    }
+}
+
+macro_rules! impl_format_arg_int {
+    ($($t:ty)*) => {$(
+        impl FormatArg for $t {
+            fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+                Some(match format_type.format_trait() {
+                    FormatTrait::Display => std::fmt::Display::fmt(self, f),
+                    FormatTrait::Debug => std::fmt::Debug::fmt(self, f),
+                    FormatTrait::Octal => std::fmt::Octal::fmt(self, f),
+                    FormatTrait::LowerHex => std::fmt::LowerHex::fmt(self, f),
+                    FormatTrait::UpperHex => std::fmt::UpperHex::fmt(self, f),
+                    FormatTrait::Binary => std::fmt::Binary::fmt(self, f),
+                    FormatTrait::LowerExp => std::fmt::LowerExp::fmt(self, f),
+                    FormatTrait::UpperExp => std::fmt::UpperExp::fmt(self, f),
+                    FormatTrait::Pointer | FormatTrait::Custom(_) => return None,
+                })
+            }
+
+            fn to_count(&self) -> Option<usize> {
+                usize::try_from(*self).ok()
+            }
+        }
+    )*}
+}
+
+impl_format_arg_int!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+
+macro_rules! impl_format_arg_float {
+    ($($t:ty)*) => {$(
+        impl FormatArg for $t {
+            fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+                Some(match format_type.format_trait() {
+                    FormatTrait::Display => std::fmt::Display::fmt(self, f),
+                    FormatTrait::Debug => std::fmt::Debug::fmt(self, f),
+                    FormatTrait::LowerExp => std::fmt::LowerExp::fmt(self, f),
+                    FormatTrait::UpperExp => std::fmt::UpperExp::fmt(self, f),
+                    _ => return None,
+                })
+            }
+        }
+    )*}
+}
+
+impl_format_arg_float!(f32 f64);
+
+macro_rules! impl_format_arg_display {
+    ($t:ty) => {
+        impl_format_arg_display!($t, |_v| None);
+    };
+    ($t:ty, |$v:ident| $text:expr) => {
+        impl FormatArg for $t {
+            fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+                Some(match format_type.format_trait() {
+                    FormatTrait::Display => std::fmt::Display::fmt(self, f),
+                    FormatTrait::Debug => std::fmt::Debug::fmt(self, f),
+                    _ => return None,
+                })
+            }
+
+            fn as_text(&self) -> Option<Cow<'_, str>> {
+                let $v = self;
+                $text
+            }
+        }
+    };
+}
+
+impl_format_arg_display!(bool);
+impl_format_arg_display!(char, |c| Some(Cow::Owned(c.to_string())));
+impl_format_arg_display!(str, |s| Some(Cow::Borrowed(s)));
+impl_format_arg_display!(String, |s| Some(Cow::Borrowed(s.as_str())));
+
+impl FormatArg for dyn std::fmt::Display + '_ {
+    fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+        match format_type.format_trait() {
+            FormatTrait::Display => Some(std::fmt::Display::fmt(self, f)),
+            _ => None,
+        }
+    }
+}
+
+/// Collections are not formattable themselves, but can be walked by `Argument::Path`.
+impl<T: FormatArg> FormatArg for [T] {
+    fn fmt(&self, _format_type: &FormatType, _f: &mut Formatter) -> Option<std::fmt::Result> {
+        None
+    }
+
+    fn index(&self, index: usize) -> Option<&dyn FormatArg> {
+        self.get(index).map(|v| v as &dyn FormatArg)
+    }
+}
+
+impl<T: FormatArg> FormatArg for Vec<T> {
+    fn fmt(&self, _format_type: &FormatType, _f: &mut Formatter) -> Option<std::fmt::Result> {
+        None
+    }
+
+    fn index(&self, index: usize) -> Option<&dyn FormatArg> {
+        self.as_slice().index(index)
+    }
+}
+
+impl<K: Borrow<str> + Eq + Hash, V: FormatArg, S: BuildHasher> FormatArg for HashMap<K, V, S> {
+    fn fmt(&self, _format_type: &FormatType, _f: &mut Formatter) -> Option<std::fmt::Result> {
+        None
+    }
+
+    fn field(&self, name: &str) -> Option<&dyn FormatArg> {
+        self.get(name).map(|v| v as &dyn FormatArg)
+    }
+}
+
+impl<K: Borrow<str> + Ord, V: FormatArg> FormatArg for BTreeMap<K, V> {
+    fn fmt(&self, _format_type: &FormatType, _f: &mut Formatter) -> Option<std::fmt::Result> {
+        None
+    }
+
+    fn field(&self, name: &str) -> Option<&dyn FormatArg> {
+        self.get(name).map(|v| v as &dyn FormatArg)
+    }
+}
+
+
+/// Source of the values referenced by a `FormatString`.
+///
+/// `Argument::Next` and `.*` precisions are numbered by the renderer and `Argument::Path` is
+/// resolved by looking up its first segment and walking the rest with `FormatArg::field` and
+/// `FormatArg::index`, so `resolve` is only ever called with `Argument::Index` and
+/// `Argument::Name`.
+pub trait Args {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg>;
+}
+
+fn lookup<'v>(args: &'v dyn Args, arg: &Argument) -> Option<&'v dyn FormatArg> {
+    match *arg {
+        Argument::Path(ref path) => {
+            let (root, rest) = path.split_first()?;
+            let root = match *root {
+                PathSegment::Field(ref name) => args.resolve(&Argument::Name(Cow::Borrowed(name))),
+                PathSegment::Index(index) => args.resolve(&Argument::Index(index)),
+            };
+            rest.iter().try_fold(root?, |value, segment| match *segment {
+                PathSegment::Field(ref name) => value.field(name),
+                PathSegment::Index(index) => value.index(index),
+            })
+        }
+        _ => args.resolve(arg),
+    }
+}
+
+fn index<'v, T: FormatArg>(values: &'v [T], arg: &Argument) -> Option<&'v dyn FormatArg> {
+    match *arg {
+        Argument::Index(index) => values.get(index).map(|v| v as &dyn FormatArg),
+        _ => None,
+    }
+}
+
+impl<T: FormatArg> Args for &[T] {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        index(self, arg)
+    }
+}
+
+impl<T: FormatArg, const N: usize> Args for [T; N] {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        index(self, arg)
+    }
+}
+
+impl<T: FormatArg> Args for Vec<T> {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        index(self, arg)
+    }
+}
+
+impl<K: Borrow<str> + Eq + Hash, V: FormatArg, S: BuildHasher> Args for HashMap<K, V, S> {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        match *arg {
+            Argument::Name(ref name) => HashMap::get(self, name.as_ref()).map(|v| v as &dyn FormatArg),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Borrow<str> + Ord, V: FormatArg> Args for BTreeMap<K, V> {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        match *arg {
+            Argument::Name(ref name) => BTreeMap::get(self, name.as_ref()).map(|v| v as &dyn FormatArg),
+            _ => None,
+        }
+    }
+}
+
+
+/// Positional and named arguments collected with a fluent interface, e.g.
+/// `ArgList::new().positional(&x).named("user", &user)`.
+#[derive(Default)]
+pub struct ArgList<'a> {
+    positional: Vec<&'a dyn FormatArg>,
+    named: Vec<(Cow<'a, str>, &'a dyn FormatArg)>,
+}
+
+impl<'a> ArgList<'a> {
+    pub fn new() -> ArgList<'a> {
+        ArgList::default()
+    }
+
+    pub fn positional<T: FormatArg>(mut self, value: &'a T) -> ArgList<'a> {
+        self.positional.push(value);
+        self
+    }
+
+    /// Adds a named argument, replacing any previous argument with the same name.
+    pub fn named<N: Into<Cow<'a, str>>, T: FormatArg>(mut self, name: N, value: &'a T) -> ArgList<'a> {
+        let name = name.into();
+        self.named.retain(|(n, _)| *n != name);
+        self.named.push((name, value));
+        self
+    }
+}
+
+impl<'a> Args for ArgList<'a> {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        match *arg {
+            Argument::Index(index) => self.positional.get(index).copied(),
+            Argument::Name(ref name) => self.named.iter().find(|(n, _)| n == name).map(|(_, v)| *v),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub enum RenderError {
+    MissingArgument {
+        arg: Argument<'static>,
+        span: Span,
+    },
+    UnsupportedFormat {
+        arg: Argument<'static>,
+        format_type: FormatType,
+        span: Span,
+    },
+    InvalidCount {
+        count: Count<'static>,
+        span: Span,
+    },
+    InvalidFormat {
+        span: Span,
+    },
+    Fmt(std::fmt::Error),
+}
+
+impl RenderError {
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            RenderError::MissingArgument { span, .. } => Some(span),
+            RenderError::UnsupportedFormat { span, .. } => Some(span),
+            RenderError::InvalidCount { span, .. } => Some(span),
+            RenderError::InvalidFormat { span } => Some(span),
+            RenderError::Fmt(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match *self {
+            RenderError::MissingArgument { ref arg, span } => write!(f, "missing argument '{}' at position {}", arg, span.start),
+            RenderError::UnsupportedFormat { ref arg, ref format_type, span } => write!(f, "argument '{}' at position {} does not support format type '{}'", arg, span.start, format_type),
+            RenderError::InvalidCount { count: Count::Argument(ref arg), span } => write!(f, "argument '{}' at position {} is not a valid count", arg, span.start),
+            RenderError::InvalidCount { count: Count::Value(value), span } => write!(f, "count {} at position {} is too large", value, span.start),
+            RenderError::InvalidFormat { span } => write!(f, "invalid format at position {}", span.start),
+            RenderError::Fmt(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            RenderError::Fmt(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::fmt::Error> for RenderError {
+    fn from(err: std::fmt::Error) -> Self {
+        RenderError::Fmt(err)
+    }
+}
+
+
+pub(super) fn argument<'v>(args: &'v dyn Args, arg: &Spanned<Argument>) -> Result<&'v dyn FormatArg, RenderError> {
+    lookup(args, arg).ok_or_else(|| RenderError::MissingArgument {
+        arg: arg.node().clone().into_owned(),
+        span: arg.span(),
+    })
+}
+
+fn count_argument(args: &dyn Args, arg: &Spanned<Argument>) -> Result<u16, RenderError> {
+    argument(args, arg)?.to_count().and_then(|c| u16::try_from(c).ok()).ok_or_else(|| RenderError::InvalidCount {
+        count: Count::Argument(arg.clone().map(Argument::into_owned)),
+        span: arg.span(),
+    })
+}
+
+pub(super) fn count(count: &Spanned<Count>, args: &dyn Args) -> Result<u16, RenderError> {
+    match **count {
+        Count::Value(value) => u16::try_from(value).map_err(|_| RenderError::InvalidCount {
+            count: Count::Value(value),
+            span: count.span(),
+        }),
+        Count::Argument(ref arg) => count_argument(args, arg),
+    }
+}
+
+/// Returns the formatting options of `spec`, except for width and precision.
+pub(super) fn base_options(spec: &FormatSpec) -> FormattingOptions {
+    let mut options = FormattingOptions::new();
+    if let Some(fill_align) = spec.fill_align {
+        if let Some(fill) = fill_align.fill {
+            options.fill(fill);
+        }
+        options.align(Some(match fill_align.align {
+            Align::Left => Alignment::Left,
+            Align::Center => Alignment::Center,
+            Align::Right => Alignment::Right,
+        }));
+    }
+    options.sign(spec.sign.map(|s| match s {
+        Sign::Plus => std::fmt::Sign::Plus,
+        Sign::Minus => std::fmt::Sign::Minus,
+    }));
+    options.alternate(spec.alter);
+    options.sign_aware_zero_pad(spec.zero);
+    options.debug_as_hex(match spec.format_type {
+        FormatType::DebugLowerHex => Some(DebugAsHex::Lower),
+        FormatType::DebugUpperHex => Some(DebugAsHex::Upper),
+        _ => None,
+    });
+    options
+}
+
+/// Returns the formatting options of `spec`, taking a `.*` precision from argument `star`.
+fn spec_options(spec: &FormatSpec, args: &dyn Args, star: usize) -> Result<FormattingOptions, RenderError> {
+    let mut options = base_options(spec);
+    if let Some(ref width) = spec.width {
+        options.width(Some(count(width, args)?));
+    }
+    if let Some(ref precision) = spec.precision {
+        match **precision {
+            Precision::Count(ref c) => options.precision(Some(count(c, args)?)),
+            Precision::Star => options.precision(Some(count_argument(args, &Spanned::new(Argument::Index(star), precision.span()))?)),
+        };
+    }
+    Ok(options)
+}
+
+pub(super) fn format_argument(value: &dyn FormatArg, arg: &Spanned<Argument>, format_type: &FormatType, f: &mut Formatter, options: &RenderOptions) -> Result<(), RenderError> {
+    if options.display_width && format_type.format_trait() == FormatTrait::Display {
+        if let Some(text) = value.as_text() {
+            return Ok(crate::width::pad(f, &text)?);
+        }
+    }
+    match value.fmt(format_type, f) {
+        Some(res) => Ok(res?),
+        None => Err(RenderError::UnsupportedFormat {
+            arg: arg.node().clone().into_owned(),
+            format_type: format_type.clone(),
+            span: arg.span(),
+        }),
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenderOptions {
+    display_width: bool,
+}
+
+impl RenderOptions {
+    pub const fn new() -> RenderOptions {
+        RenderOptions {
+            display_width: false,
+        }
+    }
+
+    /// Measures width and precision of text arguments formatted with `Display` in terminal
+    /// columns instead of chars, like `DisplayColumns`. Other arguments are formatted as usual.
+    pub fn with_display_width(mut self, display_width: bool) -> RenderOptions {
+        self.display_width = display_width;
+        self
+    }
+
+    pub fn display_width(&self) -> bool {
+        self.display_width
+    }
+}
+
+
+impl<'a> FormatString<'a> {
+    /// Formats `args` according to this format string, producing the same output as
+    /// `format!` with the same string and arguments.
+    pub fn render(&self, args: &dyn Args, out: &mut dyn Write) -> Result<(), RenderError> {
+        self.render_with(args, out, &RenderOptions::new())
+    }
+
+    pub fn render_with(&self, args: &dyn Args, out: &mut dyn Write, options: &RenderOptions) -> Result<(), RenderError> {
+        let mut next = 0;
+        for item in self.items() {
+            match **item {
+                FormatStringItem::Text(ref s) => out.write_str(s)?,
+                FormatStringItem::Escape(c) => out.write_char(c)?,
+                FormatStringItem::Format(ref fmt) => {
+                    let default_spec = FormatSpec::new();
+                    let spec = fmt.spec().map_or(&default_spec, |s| s.node());
+                    let star = next;
+                    if let Some(Precision::Star) = spec.precision.as_ref().map(|p| p.node()) {
+                        next += 1;
+                    }
+                    let next_arg;
+                    let arg = match **fmt.arg() {
+                        Argument::Next => {
+                            next += 1;
+                            next_arg = Spanned::new(Argument::Index(next - 1), fmt.arg_span());
+                            &next_arg
+                        }
+                        _ => fmt.arg(),
+                    };
+                    let value = argument(args, arg)?;
+                    let mut formatter = Formatter::new(out, spec_options(spec, args, star)?);
+                    format_argument(value, arg, &spec.format_type, &mut formatter, options)?;
+                }
+                FormatStringItem::Error(_) => return Err(RenderError::InvalidFormat { span: item.span() }),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render_to_string(&self, args: &dyn Args) -> Result<String, RenderError> {
+        let mut out = String::new();
+        self.render(args, &mut out)?;
+        Ok(out)
+    }
+}
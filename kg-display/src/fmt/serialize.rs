@@ -0,0 +1,85 @@
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor};
+use serde::ser::Serializer;
+
+use super::*;
+
+impl<T: Serialize> Serialize for Spanned<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.node.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Spanned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Spanned::from)
+    }
+}
+
+impl<'a> Serialize for FormatString<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de, 'a> Deserialize<'de> for FormatString<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        FormatStringSeed(&ParseOptions::new()).deserialize(deserializer)
+    }
+}
+
+/// Deserializes a `FormatString` from its string form like its `Deserialize` impl, but parses
+/// it with the given options, so that custom format types and field paths are accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatStringSeed<'o>(pub &'o ParseOptions);
+
+impl<'de, 'o> DeserializeSeed<'de> for FormatStringSeed<'o> {
+    type Value = FormatString<'static>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        struct FormatStringVisitor<'o>(&'o ParseOptions);
+
+        impl<'de, 'o> Visitor<'de> for FormatStringVisitor<'o> {
+            type Value = FormatString<'static>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a format string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                FormatString::parse_with(v, self.0).map(FormatString::into_owned).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(FormatStringVisitor(self.0))
+    }
+}
+
+
+/// Serializes a `FormatString` as a sequence of items instead of its string form. Use with
+/// `#[serde(with = "kg_display::fmt::tree")]`. Deserialized trees are validated by parsing
+/// their string form, so the result is the same as deserializing that string.
+pub mod tree {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(fmt_str: &FormatString, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(fmt_str.items())
+    }
+
+    pub fn deserialize<'de, 'a, D: Deserializer<'de>>(deserializer: D) -> Result<FormatString<'a>, D::Error> {
+        Seed(&ParseOptions::new()).deserialize(deserializer)
+    }
+
+    /// Deserializes a tree like `deserialize`, but validates it with the given options.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Seed<'o>(pub &'o ParseOptions);
+
+    impl<'de, 'o> DeserializeSeed<'de> for Seed<'o> {
+        type Value = FormatString<'static>;
+
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+            let items = Vec::<Spanned<FormatStringItem>>::deserialize(deserializer)?;
+            let fmt_str = FormatString(items).to_string();
+            FormatString::parse_with(&fmt_str, self.0).map(FormatString::into_owned).map_err(de::Error::custom)
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::*;
+
+pub trait Visit<'a> {
+    fn visit_format_string(&mut self, node: &FormatString<'a>) {
+        visit_format_string(self, node)
+    }
+
+    fn visit_item(&mut self, node: &Spanned<FormatStringItem<'a>>) {
+        visit_item(self, node)
+    }
+
+    fn visit_text(&mut self, _text: &str, _span: Span) {}
+
+    fn visit_escape(&mut self, _c: char, _span: Span) {}
+
+    fn visit_error(&mut self, _text: &str, _span: Span) {}
+
+    fn visit_format(&mut self, node: &Format<'a>) {
+        visit_format(self, node)
+    }
+
+    fn visit_argument(&mut self, _node: &Spanned<Argument<'a>>) {}
+
+    fn visit_format_spec(&mut self, node: &Spanned<FormatSpec<'a>>) {
+        visit_format_spec(self, node)
+    }
+
+    fn visit_width(&mut self, node: &Spanned<Count<'a>>) {
+        self.visit_count(node)
+    }
+
+    fn visit_precision(&mut self, node: &Spanned<Precision<'a>>) {
+        visit_precision(self, node)
+    }
+
+    fn visit_count(&mut self, node: &Spanned<Count<'a>>) {
+        visit_count(self, node)
+    }
+}
+
+pub fn visit_format_string<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &FormatString<'a>) {
+    for item in node.0.iter() {
+        v.visit_item(item);
+    }
+}
+
+pub fn visit_item<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &Spanned<FormatStringItem<'a>>) {
+    match **node {
+        FormatStringItem::Text(ref text) => v.visit_text(text, node.span()),
+        FormatStringItem::Escape(c) => v.visit_escape(c, node.span()),
+        FormatStringItem::Format(ref fmt) => v.visit_format(fmt),
+        FormatStringItem::Error(ref text) => v.visit_error(text, node.span()),
+    }
+}
+
+pub fn visit_format<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &Format<'a>) {
+    v.visit_argument(&node.arg);
+    if let Some(ref spec) = node.spec {
+        v.visit_format_spec(spec);
+    }
+}
+
+pub fn visit_format_spec<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &Spanned<FormatSpec<'a>>) {
+    if let Some(ref width) = node.width {
+        v.visit_width(width);
+    }
+    if let Some(ref precision) = node.precision {
+        v.visit_precision(precision);
+    }
+}
+
+pub fn visit_precision<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &Spanned<Precision<'a>>) {
+    match **node {
+        Precision::Count(ref count) => v.visit_count(count),
+        Precision::Star => {}
+    }
+}
+
+pub fn visit_count<'a, V: Visit<'a> + ?Sized>(v: &mut V, node: &Spanned<Count<'a>>) {
+    match **node {
+        Count::Argument(ref arg) => v.visit_argument(arg),
+        Count::Value(_) => {}
+    }
+}
+
+
+pub trait VisitMut<'a> {
+    fn visit_format_string_mut(&mut self, node: &mut FormatString<'a>) {
+        visit_format_string_mut(self, node)
+    }
+
+    fn visit_item_mut(&mut self, node: &mut Spanned<FormatStringItem<'a>>) {
+        visit_item_mut(self, node)
+    }
+
+    fn visit_text_mut(&mut self, _text: &mut Cow<'a, str>, _span: Span) {}
+
+    fn visit_escape_mut(&mut self, _c: &mut char, _span: Span) {}
+
+    fn visit_error_mut(&mut self, _text: &mut Cow<'a, str>, _span: Span) {}
+
+    fn visit_format_mut(&mut self, node: &mut Format<'a>) {
+        visit_format_mut(self, node)
+    }
+
+    fn visit_argument_mut(&mut self, _node: &mut Spanned<Argument<'a>>) {}
+
+    fn visit_format_spec_mut(&mut self, node: &mut Spanned<FormatSpec<'a>>) {
+        visit_format_spec_mut(self, node)
+    }
+
+    fn visit_width_mut(&mut self, node: &mut Spanned<Count<'a>>) {
+        self.visit_count_mut(node)
+    }
+
+    fn visit_precision_mut(&mut self, node: &mut Spanned<Precision<'a>>) {
+        visit_precision_mut(self, node)
+    }
+
+    fn visit_count_mut(&mut self, node: &mut Spanned<Count<'a>>) {
+        visit_count_mut(self, node)
+    }
+}
+
+pub fn visit_format_string_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut FormatString<'a>) {
+    for item in node.0.iter_mut() {
+        v.visit_item_mut(item);
+    }
+}
+
+pub fn visit_item_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut Spanned<FormatStringItem<'a>>) {
+    let span = node.span();
+    match **node {
+        FormatStringItem::Text(ref mut text) => v.visit_text_mut(text, span),
+        FormatStringItem::Escape(ref mut c) => v.visit_escape_mut(c, span),
+        FormatStringItem::Format(ref mut fmt) => v.visit_format_mut(fmt),
+        FormatStringItem::Error(ref mut text) => v.visit_error_mut(text, span),
+    }
+}
+
+pub fn visit_format_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut Format<'a>) {
+    v.visit_argument_mut(&mut node.arg);
+    if let Some(ref mut spec) = node.spec {
+        v.visit_format_spec_mut(spec);
+    }
+}
+
+pub fn visit_format_spec_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut Spanned<FormatSpec<'a>>) {
+    if let Some(ref mut width) = node.width {
+        v.visit_width_mut(width);
+    }
+    if let Some(ref mut precision) = node.precision {
+        v.visit_precision_mut(precision);
+    }
+}
+
+pub fn visit_precision_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut Spanned<Precision<'a>>) {
+    match **node {
+        Precision::Count(ref mut count) => v.visit_count_mut(count),
+        Precision::Star => {}
+    }
+}
+
+pub fn visit_count_mut<'a, V: VisitMut<'a> + ?Sized>(v: &mut V, node: &mut Spanned<Count<'a>>) {
+    match **node {
+        Count::Argument(ref mut arg) => v.visit_argument_mut(arg),
+        Count::Value(_) => {}
+    }
+}
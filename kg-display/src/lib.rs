@@ -1,9 +1,17 @@
 #![feature(char_indices_offset)]
 #![feature(iter_advance_by)]
+#![feature(formatting_options)]
 
 use std::ops::{Deref, DerefMut};
 
 pub mod fmt;
+mod value;
+mod width;
+
+pub use self::value::Value;
+pub use self::width::{DisplayColumns, display_width};
+#[cfg(feature = "serde")]
+pub use self::value::{to_value, ValueError};
 
 pub struct PrettyPrinter<'a, 'b: 'a> {
     padding: &'a str,
@@ -0,0 +1,320 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Formatter;
+use std::iter::FromIterator;
+
+use crate::fmt::{Args, Argument, FormatArg, FormatTrait, FormatType};
+
+#[cfg(feature = "serde")]
+mod serialize;
+
+#[cfg(feature = "serde")]
+pub use self::serialize::{to_value, ValueError};
+
+/// Dynamically typed value for rendering format strings at runtime.
+///
+/// Numbers support every numeric formatting trait their Rust counterparts support, lists and
+/// maps are formatted element by element and have pretty `{:#?}` output like `Vec` and
+/// `BTreeMap`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    #[default]
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Char(char),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(*self, Value::Null)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match *self {
+            Value::List(ref l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<String, Value>> {
+        match *self {
+            Value::Map(ref m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_map().and_then(|m| m.get(key))
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<&Value> {
+        self.as_list().and_then(|l| l.get(index))
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match *self {
+            Value::Null => f.pad("null"),
+            Value::Bool(b) => std::fmt::Display::fmt(&b, f),
+            Value::Int(n) => std::fmt::Display::fmt(&n, f),
+            Value::UInt(n) => std::fmt::Display::fmt(&n, f),
+            Value::Float(n) => std::fmt::Display::fmt(&n, f),
+            Value::Char(c) => std::fmt::Display::fmt(&c, f),
+            Value::String(ref s) => std::fmt::Display::fmt(s, f),
+            Value::List(ref l) => {
+                f.write_str("[")?;
+                for (i, e) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    std::fmt::Display::fmt(e, f)?;
+                }
+                f.write_str("]")
+            }
+            Value::Map(ref m) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: ", k)?;
+                    std::fmt::Display::fmt(v, f)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct DebugValue<'a>(&'a Value);
+
+impl std::fmt::Debug for DebugValue<'_> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match *self.0 {
+            Value::Null => f.pad("null"),
+            Value::Bool(b) => std::fmt::Debug::fmt(&b, f),
+            Value::Int(n) => std::fmt::Debug::fmt(&n, f),
+            Value::UInt(n) => std::fmt::Debug::fmt(&n, f),
+            Value::Float(n) => std::fmt::Debug::fmt(&n, f),
+            Value::Char(c) => std::fmt::Debug::fmt(&c, f),
+            Value::String(ref s) => std::fmt::Debug::fmt(s, f),
+            Value::List(ref l) => f.debug_list().entries(l.iter().map(DebugValue)).finish(),
+            Value::Map(ref m) => f.debug_map().entries(m.iter().map(|(k, v)| (k, DebugValue(v)))).finish(),
+        }
+    }
+}
+
+impl FormatArg for Value {
+    fn fmt(&self, format_type: &FormatType, f: &mut Formatter) -> Option<std::fmt::Result> {
+        match (format_type.format_trait(), self) {
+            (FormatTrait::Display, _) => Some(std::fmt::Display::fmt(self, f)),
+            (FormatTrait::Debug, _) => Some(std::fmt::Debug::fmt(&DebugValue(self), f)),
+            (_, Value::Int(n)) => n.fmt(format_type, f),
+            (_, Value::UInt(n)) => n.fmt(format_type, f),
+            (_, Value::Float(n)) => n.fmt(format_type, f),
+            _ => None,
+        }
+    }
+
+    fn to_count(&self) -> Option<usize> {
+        match *self {
+            Value::Int(n) => n.to_count(),
+            Value::UInt(n) => n.to_count(),
+            _ => None,
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&dyn FormatArg> {
+        Value::get(self, name).map(|v| v as &dyn FormatArg)
+    }
+
+    fn index(&self, index: usize) -> Option<&dyn FormatArg> {
+        self.get_index(index).map(|v| v as &dyn FormatArg)
+    }
+
+    fn as_text(&self) -> Option<Cow<'_, str>> {
+        match *self {
+            Value::String(ref s) => Some(Cow::Borrowed(s)),
+            Value::Char(c) => Some(Cow::Owned(c.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Lists resolve indexed arguments and maps resolve named arguments.
+impl Args for Value {
+    fn resolve(&self, arg: &Argument) -> Option<&dyn FormatArg> {
+        let value = match *arg {
+            Argument::Index(index) => self.get_index(index),
+            Argument::Name(ref name) => Value::get(self, name),
+            _ => None,
+        };
+        value.map(|v| v as &dyn FormatArg)
+    }
+}
+
+
+macro_rules! impl_from {
+    ($variant:ident: $($t:ty)*) => {$(
+        impl From<$t> for Value {
+            fn from(value: $t) -> Self {
+                Value::$variant(value.into())
+            }
+        }
+    )*}
+}
+
+impl_from!(Bool: bool);
+impl_from!(Int: i8 i16 i32 i64);
+impl_from!(UInt: u8 u16 u32 u64);
+impl_from!(Float: f64);
+impl_from!(Char: char);
+impl_from!(String: &str String);
+
+/// Converts through the shortest decimal form of the `f32`, so that the value formats like
+/// the `f32` rather than its exact widened `f64` (`1.1` instead of `1.100000023841858`).
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Float(value.to_string().parse().unwrap())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(value: Vec<T>) -> Self {
+        Value::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> FromIterator<T> for Value {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Value::List(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Value {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Value::Map(iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(value: BTreeMap<String, Value>) -> Self {
+        Value::Map(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmt::FormatString;
+
+    fn render(fmt: &str, value: &Value) -> String {
+        let args: &[&dyn FormatArg] = &[value];
+        FormatString::parse(fmt).unwrap().render_to_string(&args).unwrap()
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(render("[{:>6}]", &Value::Null), "[  null]");
+        assert_eq!(render("{0:?} {0}", &Value::from("a\"b")), "\"a\\\"b\" a\"b");
+        assert_eq!(render("{0:#06x} {0:o} {0:b} {0:e} {0:X?}", &Value::from(-42i32)), format!("{0:#06x} {0:o} {0:b} {0:e} {0:X?}", -42i64));
+        assert_eq!(render("{0:+.2} {0:E} {0:?}", &Value::from(1.5f64)), format!("{0:+.2} {0:E} {0:?}", 1.5f64));
+        assert_eq!(render("{0:^7}|{0:?}", &Value::from('x')), "   x   |'x'");
+        assert_eq!(render("{:<6}|", &Value::from(true)), "true  |");
+        assert_eq!(render("{0} {0:?} {0:e}", &Value::from(1.1f32)), format!("{0} {0:?} {0:e}", 1.1f32));
+        assert_eq!(render("{0} {0:?}", &Value::from(-3.4028235e38f32)), format!("{0} {0:?}", -3.4028235e38f32));
+        assert_eq!(render("{}", &Value::from(vec![f32::NAN, f32::NEG_INFINITY])), "[NaN, -inf]");
+
+        let args: &[&dyn FormatArg] = &[&Value::from(true)];
+        assert!(FormatString::parse("{:x}").unwrap().render_to_string(&args).is_err());
+        let args: &[&dyn FormatArg] = &[&Value::from("s"), &Value::from(4u8)];
+        assert_eq!(FormatString::parse("{:>1$}").unwrap().render_to_string(&args).unwrap(), "   s");
+    }
+
+    #[test]
+    fn collections() {
+        let list: Value = vec![Value::from(1), Value::from("two"), Value::Null].into();
+        assert_eq!(render("{}", &list), "[1, two, null]");
+        assert_eq!(render("{:?}", &list), "[1, \"two\", null]");
+        assert_eq!(render("{:#?}", &list), "[\n    1,\n    \"two\",\n    null,\n]");
+
+        let map: Value = vec![("b", Value::from(vec![10u8, 11])), ("a", Value::from(255u8))].into_iter().collect();
+        assert_eq!(render("{}", &map), "{a: 255, b: [10, 11]}");
+        assert_eq!(render("{:x?}", &map), "{\"a\": ff, \"b\": [a, b]}");
+        assert_eq!(render("{:#?}", &map), "{\n    \"a\": 255,\n    \"b\": [\n        10,\n        11,\n    ],\n}");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_serialize() {
+        use serde::Serialize;
+
+        #[derive(Serialize)]
+        struct Line {
+            sku: &'static str,
+            qty: u32,
+            price: f64,
+            weight: f32,
+        }
+
+        #[derive(Serialize)]
+        enum Status {
+            Open,
+            Shipped { days: u8 },
+        }
+
+        #[derive(Serialize)]
+        struct Order {
+            id: u64,
+            customer: Option<String>,
+            lines: Vec<Line>,
+            status: Status,
+            previous: Status,
+            tags: BTreeMap<u8, char>,
+        }
+
+        let order = Order {
+            id: 17,
+            customer: None,
+            lines: vec![Line { sku: "A-1", qty: 2, price: 9.5, weight: 0.3 }],
+            status: Status::Shipped { days: 3 },
+            previous: Status::Open,
+            tags: vec![(1, 'x')].into_iter().collect(),
+        };
+        let value = to_value(&order).unwrap();
+        let options = crate::fmt::ParseOptions::new().with_field_paths(true);
+        let f = FormatString::parse_with("#{id:05} {customer} {lines[0].sku}x{lines[0].qty} {lines[0].price:.2} {lines[0].weight}kg {status.Shipped.days} {previous} {tags:?}", &options).unwrap();
+        assert_eq!(f.render_to_string(&value).unwrap(), "#00017 null A-1x2 9.50 0.3kg 3 Open {\"1\": 'x'}");
+
+        let json: serde_json::Value = serde_json::from_str(r#"{"user": {"name": "ann", "roles": ["admin", "dev"]}, "n": -3}"#).unwrap();
+        let value = to_value(&json).unwrap();
+        let f = FormatString::parse_with("{user.name:>5}: {user.roles:?} {n:+}", &options).unwrap();
+        assert_eq!(f.render_to_string(&value).unwrap(), "  ann: [\"admin\", \"dev\"] -3");
+        assert_eq!(serde_json::to_value(&value).unwrap(), json);
+
+        let positional = to_value(&("a", 1.5)).unwrap();
+        assert_eq!(FormatString::parse("{1}{0}").unwrap().render_to_string(&positional).unwrap(), "1.5a");
+        assert!(to_value(&vec![(vec![1], 2)].into_iter().collect::<std::collections::HashMap<_, _>>()).is_err());
+    }
+}
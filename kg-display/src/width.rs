@@ -0,0 +1,108 @@
+use std::fmt::{Alignment, Formatter, Write};
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Displays a value with width and precision measured in terminal columns rather than chars,
+/// so that wide (e.g. CJK) characters, zero-width characters and grapheme clusters line up.
+///
+/// The value is formatted without any options. Precision truncates the output to the given
+/// number of columns, without splitting grapheme clusters, and width pads it with the fill
+/// character, aligned to the left by default. Columns a wide fill character cannot cover are
+/// padded with spaces next to the value.
+pub struct DisplayColumns<T: std::fmt::Display>(pub T);
+
+impl<T: std::fmt::Display> std::fmt::Display for DisplayColumns<T> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        if f.width().is_none() && f.precision().is_none() {
+            return write!(f, "{}", self.0);
+        }
+        pad(f, &self.0.to_string())
+    }
+}
+
+
+/// Number of terminal columns taken by `s`.
+pub fn display_width(s: &str) -> usize {
+    s.graphemes(true).map(grapheme_width).sum()
+}
+
+fn grapheme_width(g: &str) -> usize {
+    // a grapheme cluster is displayed as a single glyph, at most two columns wide
+    g.width().min(2)
+}
+
+/// Like `Formatter::pad`, but measures width and precision in terminal columns.
+pub(crate) fn pad(f: &mut Formatter, s: &str) -> std::fmt::Result {
+    let mut width = 0;
+    let s = match f.precision() {
+        Some(precision) => {
+            let mut end = 0;
+            for (i, g) in s.grapheme_indices(true) {
+                let w = grapheme_width(g);
+                if width + w > precision {
+                    break;
+                }
+                width += w;
+                end = i + g.len();
+            }
+            &s[..end]
+        }
+        None => {
+            width = display_width(s);
+            s
+        }
+    };
+
+    let padding = match f.width() {
+        Some(w) if w > width => w - width,
+        _ => return f.write_str(s),
+    };
+    let fill = f.fill();
+    let fill_width = fill.to_string().width().max(1);
+    let (pre, post) = match f.align().unwrap_or(Alignment::Left) {
+        Alignment::Left => (0, padding),
+        Alignment::Right => (padding, 0),
+        Alignment::Center => (padding / 2, padding.div_ceil(2)),
+    };
+    for _ in 0..pre / fill_width {
+        f.write_char(fill)?;
+    }
+    for _ in 0..pre % fill_width {
+        f.write_char(' ')?;
+    }
+    f.write_str(s)?;
+    for _ in 0..post % fill_width {
+        f.write_char(' ')?;
+    }
+    for _ in 0..post / fill_width {
+        f.write_char(fill)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_columns() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("e\u{301}te\u{301}"), 3);
+        assert_eq!(display_width("👨‍👩‍👧!"), 3);
+
+        assert_eq!(format!("[{:6}]", DisplayColumns("日本")), "[日本  ]");
+        assert_eq!(format!("[{:>6}]", DisplayColumns("日本")), "[  日本]");
+        assert_eq!(format!("[{:*^7}]", DisplayColumns("日本")), "[*日本**]");
+        assert_eq!(format!("[{:5.3}]", DisplayColumns("日本語")), "[日   ]");
+        assert_eq!(format!("[{:<4.3}]", DisplayColumns("e\u{301}te\u{301}s")), "[e\u{301}te\u{301} ]");
+        assert_eq!(format!("[{:4}]", DisplayColumns(12)), "[12  ]");
+        assert_eq!(format!("[{}]", DisplayColumns("日本")), "[日本]");
+        assert_eq!(format!("[{:＿>6}]", DisplayColumns("ab")), "[＿＿ab]");
+        assert_eq!(format!("[{:＿>5}]", DisplayColumns("ab")), "[＿ ab]");
+        assert_eq!(format!("[{:＿^9}]", DisplayColumns("ab")), "[＿ ab＿＿]");
+        assert_eq!(display_width(&format!("{:＿<7}", DisplayColumns("日本"))), 7);
+    }
+}
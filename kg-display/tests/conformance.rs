@@ -0,0 +1,95 @@
+extern crate kg_display;
+
+use std::io::Write;
+use std::process::Command;
+
+use kg_display::fmt::FormatString;
+
+const CORPUS: &str = include_str!("data/format_args.txt");
+
+fn corpus() -> Vec<(bool, &'static str)> {
+    CORPUS.lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut parts = l.splitn(2, '\t');
+            let verdict = parts.next().unwrap();
+            let input = parts.next().expect("missing tab separator in corpus line");
+            match verdict {
+                "ok" => (true, input),
+                "err" => (false, input),
+                _ => panic!("invalid corpus verdict '{}'", verdict),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn corpus_matches_parser() {
+    let mut failures = Vec::new();
+    for (accepted, input) in corpus() {
+        match FormatString::parse(input) {
+            Ok(f) if accepted => {
+                let out = f.to_string();
+                match FormatString::parse(&out) {
+                    Ok(ref f) if f.to_string() == out => {}
+                    r => failures.push(format!("{:?}: canonical form {:?} does not round-trip: {:?}", input, out, r)),
+                }
+            }
+            Ok(_) => failures.push(format!("{:?}: accepted, but rejected by rustc", input)),
+            Err(ref err) if accepted => failures.push(format!("{:?}: rejected ({}), but accepted by rustc", input, err)),
+            Err(_) => {}
+        }
+    }
+    assert!(failures.is_empty(), "{} corpus mismatches:\n{}", failures.len(), failures.join("\n"));
+}
+
+#[test]
+#[ignore]
+fn corpus_matches_rustc() {
+    let corpus = corpus();
+    let mut inputs: Vec<(bool, String)> = corpus.iter().map(|&(accepted, s)| (accepted, s.to_string())).collect();
+    for &(accepted, s) in corpus.iter() {
+        if accepted {
+            inputs.push((true, FormatString::parse(s).unwrap().to_string()));
+        }
+    }
+
+    let dir = std::env::temp_dir().join(format!("kg-display-corpus-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("corpus.rs");
+    let first_line = 3;
+    {
+        let mut f = std::fs::File::create(&src).unwrap();
+        writeln!(f, "#![allow(unused)]").unwrap();
+        writeln!(f, "pub fn f() {{").unwrap();
+        for (_, s) in inputs.iter() {
+            writeln!(f, "    let _ = format!(r##\"{}\"##, 1, 2, 3, 4, 5);", s).unwrap();
+        }
+        writeln!(f, "}}").unwrap();
+    }
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let out = Command::new(rustc)
+        .args(["--crate-type", "lib", "--emit=metadata", "--error-format=short", "-o"])
+        .arg(dir.join("corpus.rmeta"))
+        .arg(&src)
+        .output()
+        .expect("cannot run rustc");
+    std::fs::remove_dir_all(&dir).ok();
+
+    let mut rejected = vec![false; inputs.len()];
+    for line in String::from_utf8_lossy(&out.stderr).lines() {
+        if !line.contains("error: invalid format string") && !line.contains("error: unknown format trait") {
+            continue;
+        }
+        let (_, pos) = line.split_once(".rs:").unwrap();
+        let line_no: usize = pos.split(':').next().unwrap().parse().unwrap();
+        rejected[line_no - first_line] = true;
+    }
+
+    let failures: Vec<String> = inputs.iter().zip(rejected.iter())
+        .filter(|&(&(accepted, _), &rejected)| accepted == rejected)
+        .map(|((accepted, s), _)| format!("{:?}: expected {}", s, if *accepted { "ok" } else { "err" }))
+        .collect();
+    assert!(failures.is_empty(), "{} corpus entries disagree with rustc:\n{}", failures.len(), failures.join("\n"));
+}